@@ -0,0 +1,61 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
+
+use crate::config::CloakConfig;
+
+/// Cloak a host, see `CloakConfig`. `host` is the IP as a string
+/// if the hostname couldn't be looked up.
+///
+/// - IPv4 `a.b.c.d` becomes `H(a.b.c.d).H(a.b.c).H(a.b).IP`
+/// - IPv6 becomes `H(/128):H(/64):H(/32):IP`
+/// - Hostnames become `prefix-H(host).domain`, keeping all but the first label
+pub fn cloak_host(config: &CloakConfig, host: &str, ip: IpAddr) -> String {
+    if host != ip.to_string() {
+        let domain = host.split_once('.').map(|(_, domain)| domain);
+        return match domain {
+            Some(domain) => format!("{}-{}.{}", config.prefix, hash(config, host), domain),
+            None => format!("{}-{}", config.prefix, hash(config, host)),
+        };
+    }
+
+    let parts = match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            [
+                format!("{}.{}.{}.{}", a, b, c, d),
+                format!("{}.{}.{}", a, b, c),
+                format!("{}.{}", a, b),
+            ]
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let prefix = |count: usize| {
+                segments[..count]
+                    .iter()
+                    .map(|segment| format!("{:x}", segment))
+                    .collect::<Vec<_>>()
+                    .join(":")
+            };
+            [prefix(8), prefix(4), prefix(2)]
+        }
+    };
+    let separator = if ip.is_ipv4() { "." } else { ":" };
+    let hashes = parts
+        .iter()
+        .map(|part| hash(config, part))
+        .collect::<Vec<_>>();
+    format!("{}{}IP", hashes.join(separator), separator)
+}
+
+/// Keyed hash of a host part, as 8 uppercase hex digits.
+fn hash(config: &CloakConfig, text: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(config.key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(text.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    digest[..4]
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect()
+}
@@ -1,4 +1,15 @@
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{hash_map::RandomState, BTreeMap};
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{mask, models::User};
+
+/// Location of the server configuration, relative to the working directory.
+pub const CONFIG_PATH: &str = "config.toml";
 
 #[derive(Serialize, Deserialize)]
 pub enum CaseMap {
@@ -20,29 +31,760 @@ impl ToString for CaseMap {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct AdminConfig {
+    pub location: String,
+    pub description: String,
+    pub email: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            location: String::from("Somewhere on the internet"),
+            description: String::from("Myriad IRC server"),
+            email: String::from("admin@example.com"),
+        }
+    }
+}
+
+/// Command throttling. Every client has a bucket of `burst` tokens that
+/// refills at `rate` tokens per second. Commands cost their penalty in
+/// tokens, and are delayed while the bucket is empty.
+#[derive(Serialize, Deserialize)]
+pub struct FloodConfig {
+    #[serde(default = "FloodConfig::default_burst")]
+    pub burst: u32,
+    #[serde(default = "FloodConfig::default_rate")]
+    pub rate: u32,
+    /// Number of delayed commands at which a client is disconnected.
+    #[serde(default = "FloodConfig::default_max_queue")]
+    pub max_queue: u32,
+    /// Penalty of commands not listed in `penalties`.
+    #[serde(default = "FloodConfig::default_penalty")]
+    pub default_penalty: u32,
+    /// `user@host` masks of clients that aren't throttled, e.g. bots.
+    #[serde(default)]
+    pub exempt: Vec<String>,
+    #[serde(default = "FloodConfig::default_penalties")]
+    pub penalties: BTreeMap<String, u32>,
+}
+
+impl FloodConfig {
+    fn default_burst() -> u32 {
+        10
+    }
+    fn default_rate() -> u32 {
+        1
+    }
+    fn default_max_queue() -> u32 {
+        20
+    }
+    fn default_penalty() -> u32 {
+        1
+    }
+    fn default_penalties() -> BTreeMap<String, u32> {
+        [
+            ("PRIVMSG", 2),
+            ("JOIN", 2),
+            ("WHO", 2),
+            ("LIST", 3),
+            ("PONG", 0),
+            // Checking the password hash blocks the server for a while
+            ("OPER", 10),
+            ("AUTHENTICATE", 3),
+        ]
+        .iter()
+        .map(|(command, penalty)| (command.to_string(), *penalty))
+        .collect()
+    }
+
+    /// Get the penalty of a raw IRC line.
+    pub fn penalty(&self, line: &str) -> u32 {
+        let command = line
+            .split_whitespace()
+            .find(|word| !word.starts_with('@') && !word.starts_with(':'))
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        self.penalties
+            .get(&command)
+            .copied()
+            .unwrap_or(self.default_penalty)
+    }
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        Self {
+            burst: FloodConfig::default_burst(),
+            rate: FloodConfig::default_rate(),
+            max_queue: FloodConfig::default_max_queue(),
+            default_penalty: FloodConfig::default_penalty(),
+            exempt: vec![],
+            penalties: FloodConfig::default_penalties(),
+        }
+    }
+}
+
+/// Host cloaking. Cloaks are keyed hashes of the real host that keep
+/// its structure, so masks like `*.ABCD1234.IP` still work in bans.
+#[derive(Serialize, Deserialize)]
+pub struct CloakConfig {
+    /// Cloak the host of new clients (user mode `+x`).
+    #[serde(default)]
+    pub enabled: bool,
+    /// Secret key of the cloak hashes. Changing it changes all cloaks.
+    #[serde(default)]
+    pub key: String,
+    /// Prefix of cloaked hostnames, e.g. `myriad-ABCD1234.example.com`.
+    #[serde(default = "CloakConfig::default_prefix")]
+    pub prefix: String,
+}
+
+impl CloakConfig {
+    fn default_prefix() -> String {
+        String::from("myriad")
+    }
+
+    /// Generate a random key for new configurations.
+    fn random_key() -> String {
+        (0..2)
+            .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+            .collect()
+    }
+}
+
+impl Default for CloakConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            key: String::new(),
+            prefix: CloakConfig::default_prefix(),
+        }
+    }
+}
+
+/// Message history of channels and private conversations, see CHATHISTORY.
+#[derive(Serialize, Deserialize)]
+pub struct HistoryConfig {
+    /// Messages kept per channel or conversation, 0 disables the history.
+    #[serde(default = "HistoryConfig::default_max_messages")]
+    pub max_messages: usize,
+    /// Seconds messages are kept, 0 keeps them until newer ones push them out.
+    #[serde(default)]
+    pub max_age: u64,
+    /// Maximum number of messages returned by a single CHATHISTORY command.
+    #[serde(default = "HistoryConfig::default_max_results")]
+    pub max_results: u32,
+    /// Append-only file the history is persisted to, it's kept in memory only if unset.
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Retention of channels matching a mask, the first matching block applies.
+    #[serde(default, rename = "channel", skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<HistoryRetentionConfig>,
+}
+
+impl HistoryConfig {
+    fn default_max_messages() -> usize {
+        1000
+    }
+    fn default_max_results() -> u32 {
+        100
+    }
+
+    /// Get the maximum number of messages and their maximum age for a
+    /// channel, or for private conversations if no channel is given.
+    pub fn retention(&self, channel: Option<&str>) -> (usize, u64) {
+        self.channels
+            .iter()
+            .find(|retention| {
+                channel.is_some_and(|channel| mask::matches(&retention.mask, channel))
+            })
+            .map(|retention| {
+                (
+                    retention.max_messages.unwrap_or(self.max_messages),
+                    retention.max_age.unwrap_or(self.max_age),
+                )
+            })
+            .unwrap_or((self.max_messages, self.max_age))
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: HistoryConfig::default_max_messages(),
+            max_age: 0,
+            max_results: HistoryConfig::default_max_results(),
+            file: None,
+            channels: vec![],
+        }
+    }
+}
+
+/// Retention overrides for channels matching `mask`.
+#[derive(Serialize, Deserialize)]
+pub struct HistoryRetentionConfig {
+    pub mask: String,
+    #[serde(default)]
+    pub max_messages: Option<usize>,
+    #[serde(default)]
+    pub max_age: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Privilege {
+    /// Receive server notices (user mode `+s`).
+    ServerNotices,
+    Kill,
+    Rehash,
+    Die,
+    /// Manage server bans.
+    Ban,
+    /// See hidden channels and real hosts.
+    SeeHidden,
+    /// Override channel modes.
+    Override,
+    Wallops,
+    /// Send and receive GLOBOPS.
+    Globops,
+    /// Change the displayed host of users using CHGHOST.
+    Chghost,
+}
+
+impl Privilege {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ServerNotices => "server_notices",
+            Self::Kill => "kill",
+            Self::Rehash => "rehash",
+            Self::Die => "die",
+            Self::Ban => "ban",
+            Self::SeeHidden => "see_hidden",
+            Self::Override => "override",
+            Self::Wallops => "wallops",
+            Self::Globops => "globops",
+            Self::Chghost => "chghost",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OperClassConfig {
+    pub name: String,
+    pub privileges: Vec<Privilege>,
+}
+
+/// Connection class (I-line), assigned to clients when they connect.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConnectClassConfig {
+    pub name: String,
+    /// IP addresses, CIDR ranges or wildcard IP masks of clients in this
+    /// class. Classes are assigned before the hostname is looked up.
+    #[serde(default = "ConnectClassConfig::default_masks")]
+    pub masks: Vec<String>,
+    /// Maximum number of connections from a single IP address.
+    #[serde(default = "ConnectClassConfig::default_max_per_ip")]
+    pub max_per_ip: u32,
+    /// Maximum number of connections in this class.
+    #[serde(default = "ConnectClassConfig::default_max_clients")]
+    pub max_clients: u32,
+    /// Seconds of inactivity before the server sends a PING.
+    #[serde(default = "ConnectClassConfig::default_ping_frequency")]
+    pub ping_frequency: u64,
+    /// Maximum number of bytes waiting to be sent to a client.
+    #[serde(default = "ConnectClassConfig::default_sendq")]
+    pub sendq: u32,
+    /// Seconds a client may take to complete registration.
+    #[serde(default = "ConnectClassConfig::default_registration_timeout")]
+    pub registration_timeout: u64,
+}
+
+impl ConnectClassConfig {
+    fn default_masks() -> Vec<String> {
+        vec![String::from("*")]
+    }
+    fn default_max_per_ip() -> u32 {
+        5
+    }
+    fn default_max_clients() -> u32 {
+        1000
+    }
+    fn default_ping_frequency() -> u64 {
+        120
+    }
+    fn default_sendq() -> u32 {
+        512 * 1024
+    }
+    fn default_registration_timeout() -> u64 {
+        30
+    }
+}
+
+impl Default for ConnectClassConfig {
+    fn default() -> Self {
+        Self {
+            name: String::from("default"),
+            masks: ConnectClassConfig::default_masks(),
+            max_per_ip: ConnectClassConfig::default_max_per_ip(),
+            max_clients: ConnectClassConfig::default_max_clients(),
+            ping_frequency: ConnectClassConfig::default_ping_frequency(),
+            sendq: ConnectClassConfig::default_sendq(),
+            registration_timeout: ConnectClassConfig::default_registration_timeout(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OperConfig {
+    pub name: String,
+    /// bcrypt hash of the password, see `myriad mkpasswd`.
+    pub password: String,
+    /// `user@host` masks the operator may connect from, only local connections by default.
+    #[serde(default = "OperConfig::default_hosts")]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub require_tls: bool,
+    /// Required TLS client certificate fingerprint.
+    #[serde(default)]
+    pub certfp: Option<String>,
+    /// Privilege class granted to the operator.
+    #[serde(default = "OperConfig::default_class")]
+    pub class: String,
+}
+
+impl OperConfig {
+    fn default_hosts() -> Vec<String> {
+        vec![
+            String::from("*@127.0.0.1"),
+            String::from("*@::1"),
+            String::from("*@localhost"),
+        ]
+    }
+    fn default_class() -> String {
+        String::from("admin")
+    }
+}
+
+/// Account users can log into using SASL PLAIN.
+#[derive(Serialize, Deserialize)]
+pub struct AccountConfig {
+    pub name: String,
+    /// bcrypt hash of the password, see `myriad mkpasswd`.
+    pub password: String,
+    /// Host shown for users logged into the account.
+    #[serde(default)]
+    pub vhost: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ServerConfig {
     pub name: String,
+    #[serde(default = "ServerConfig::default_network")]
+    pub network: String,
     pub host: String,
     pub port: u16,
     pub motd: String,
+    #[serde(default)]
+    pub motd_file: Option<String>,
+    /// Location of the persistent K-line, D-line and G-line database.
+    #[serde(default = "ServerConfig::default_ban_file")]
+    pub ban_file: String,
+    /// Look up the hostname of connecting clients.
+    #[serde(default = "ServerConfig::default_resolve_hostnames")]
+    pub resolve_hostnames: bool,
+    /// Query the identd (RFC 1413) of connecting clients.
+    #[serde(default = "ServerConfig::default_ident_lookups")]
+    pub ident_lookups: bool,
+    #[serde(default = "ServerConfig::default_ident_port")]
+    pub ident_port: u16,
+    /// Seconds to wait for hostname and ident lookups.
+    #[serde(default = "ServerConfig::default_lookup_timeout")]
+    pub lookup_timeout: u64,
+    /// DNS server (`ip` or `ip:port`) to query instead of the system resolver.
+    #[serde(default)]
+    pub dns_server: Option<String>,
     #[serde(default = "ServerConfig::default_feat_awaylen")]
     pub feat_awaylen: u32,
     #[serde(default = "ServerConfig::default_feat_casemap")]
     pub feat_casemap: CaseMap,
+    #[serde(default = "ServerConfig::default_feat_nicklen")]
+    pub feat_nicklen: u32,
+    #[serde(default = "ServerConfig::default_feat_channellen")]
+    pub feat_channellen: u32,
+    #[serde(default = "ServerConfig::default_feat_topiclen")]
+    pub feat_topiclen: u32,
+    /// Maximum length of realnames.
+    #[serde(default = "ServerConfig::default_feat_namelen")]
+    pub feat_namelen: u32,
+    #[serde(default = "ServerConfig::default_feat_kicklen")]
+    pub feat_kicklen: u32,
+    #[serde(default = "ServerConfig::default_feat_maxtargets")]
+    pub feat_maxtargets: u32,
+    #[serde(default = "ServerConfig::default_feat_chanlimit")]
+    pub feat_chanlimit: u32,
+    #[serde(default = "ServerConfig::default_feat_modes")]
+    pub feat_modes: u32,
+    /// Maximum number of nicknames a client may watch using MONITOR.
+    #[serde(default = "ServerConfig::default_feat_monitor")]
+    pub feat_monitor: u32,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub flood: FloodConfig,
+    #[serde(default)]
+    pub cloak: CloakConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default = "ServerConfig::default_oper_classes", rename = "oper_class")]
+    pub oper_classes: Vec<OperClassConfig>,
+    #[serde(default = "ServerConfig::default_classes", rename = "class")]
+    pub classes: Vec<ConnectClassConfig>,
+    #[serde(default, rename = "oper", skip_serializing_if = "Vec::is_empty")]
+    pub opers: Vec<OperConfig>,
+    #[serde(default, rename = "account", skip_serializing_if = "Vec::is_empty")]
+    pub accounts: Vec<AccountConfig>,
 }
 
 // Default values for deserialization
 impl ServerConfig {
+    fn default_network() -> String {
+        String::from("Myriad")
+    }
+    fn default_ban_file() -> String {
+        String::from("bans.json")
+    }
+    fn default_resolve_hostnames() -> bool {
+        true
+    }
+    fn default_ident_lookups() -> bool {
+        true
+    }
+    fn default_ident_port() -> u16 {
+        113
+    }
+    fn default_lookup_timeout() -> u64 {
+        5
+    }
     fn default_feat_awaylen() -> u32 {
         255
     }
     fn default_feat_casemap() -> CaseMap {
         CaseMap::Ascii
     }
+    fn default_feat_nicklen() -> u32 {
+        30
+    }
+    fn default_feat_channellen() -> u32 {
+        50
+    }
+    fn default_feat_topiclen() -> u32 {
+        390
+    }
+    fn default_feat_namelen() -> u32 {
+        128
+    }
+    fn default_feat_kicklen() -> u32 {
+        255
+    }
+    fn default_feat_maxtargets() -> u32 {
+        4
+    }
+    fn default_feat_chanlimit() -> u32 {
+        20
+    }
+    fn default_feat_modes() -> u32 {
+        4
+    }
+    fn default_feat_monitor() -> u32 {
+        100
+    }
+    fn default_classes() -> Vec<ConnectClassConfig> {
+        vec![ConnectClassConfig::default()]
+    }
+    fn default_oper_classes() -> Vec<OperClassConfig> {
+        vec![
+            OperClassConfig {
+                name: String::from("helper"),
+                privileges: vec![Privilege::ServerNotices, Privilege::Globops],
+            },
+            OperClassConfig {
+                name: String::from("admin"),
+                privileges: vec![
+                    Privilege::ServerNotices,
+                    Privilege::Kill,
+                    Privilege::Rehash,
+                    Privilege::Die,
+                    Privilege::Ban,
+                    Privilege::SeeHidden,
+                    Privilege::Override,
+                    Privilege::Wallops,
+                    Privilege::Globops,
+                    Privilege::Chghost,
+                ],
+            },
+        ]
+    }
 }
 
 impl ServerConfig {
+    pub fn read_or_create(path: &str) -> Result<Self> {
+        if std::path::Path::new(path).exists() {
+            Self::read(path)
+        } else {
+            Self::create(path)
+        }
+    }
+
+    fn create(path: &str) -> Result<Self> {
+        // Create default configuration
+        let config = Self::default();
+
+        // Serialize config to string
+        match toml::to_string(&config) {
+            Ok(config_text) => {
+                // Write to file
+                let mut file = File::create(path)?;
+                file.write_all(config_text.as_ref())?;
+
+                // Return the configuration
+                Ok(config)
+            }
+            Err(_) => Err(anyhow!("Unable to serialize default config.")),
+        }
+    }
+
+    /// Read and validate the configuration at the given path.
+    pub fn read(path: &str) -> Result<Self> {
+        // Open config file
+        let mut config_file = File::open(path)?;
+
+        // Read file into buffer
+        let mut config_text = String::new();
+        config_file.read_to_string(&mut config_text)?;
+
+        // Deserialize configuration
+        let config: Self = toml::from_str(&config_text)
+            .map_err(|err| anyhow!("Unable to deserialize config: {}", err))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the configuration for values the server can't work with.
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty() {
+            return Err(anyhow!("Invalid server name: {:?}", self.name));
+        }
+        if self.network.is_empty() || self.network.contains(' ') {
+            return Err(anyhow!("Invalid network name: {:?}", self.network));
+        }
+        if self.port == 0 {
+            return Err(anyhow!("Invalid port: 0"));
+        }
+        if self.dns_server.is_some() && self.dns_server().is_none() {
+            return Err(anyhow!("Invalid dns_server: {:?}", self.dns_server));
+        }
+
+        let limits = [
+            ("feat_nicklen", self.feat_nicklen),
+            ("feat_channellen", self.feat_channellen),
+            ("feat_topiclen", self.feat_topiclen),
+            ("feat_namelen", self.feat_namelen),
+            ("feat_kicklen", self.feat_kicklen),
+            ("feat_maxtargets", self.feat_maxtargets),
+            ("feat_chanlimit", self.feat_chanlimit),
+            ("feat_modes", self.feat_modes),
+            ("feat_monitor", self.feat_monitor),
+        ];
+        if let Some((key, _)) = limits.iter().find(|(_, value)| *value == 0) {
+            return Err(anyhow!("{} must be greater than zero", key));
+        }
+
+        if self.flood.burst == 0 || self.flood.rate == 0 {
+            return Err(anyhow!(
+                "flood.burst and flood.rate must be greater than zero"
+            ));
+        }
+        if let Some((command, _)) = self
+            .flood
+            .penalties
+            .iter()
+            .find(|(_, penalty)| **penalty > self.flood.burst)
+        {
+            return Err(anyhow!("Penalty of {} exceeds flood.burst", command));
+        }
+        if self.flood.default_penalty > self.flood.burst {
+            return Err(anyhow!("flood.default_penalty exceeds flood.burst"));
+        }
+
+        if self.cloak.enabled && self.cloak.key.len() < 16 {
+            return Err(anyhow!(
+                "cloak.key must be at least 16 characters to enable cloaking"
+            ));
+        }
+        if self.cloak.prefix.is_empty()
+            || !self
+                .cloak
+                .prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(anyhow!("Invalid cloak prefix: {:?}", self.cloak.prefix));
+        }
+
+        if self.history.max_results == 0 {
+            return Err(anyhow!("history.max_results must be greater than zero"));
+        }
+
+        if self.classes.is_empty() {
+            return Err(anyhow!("At least one connection class is required"));
+        }
+        for (index, class) in self.classes.iter().enumerate() {
+            if self.classes[..index]
+                .iter()
+                .any(|other| other.name == class.name)
+            {
+                return Err(anyhow!("Duplicate connection class: {}", class.name));
+            }
+            if class.max_per_ip == 0 || class.max_clients == 0 || class.sendq == 0 {
+                return Err(anyhow!(
+                    "Connection class {} must allow at least one client",
+                    class.name
+                ));
+            }
+            if let Some(mask) = class.masks.iter().find(|mask| !mask::is_address_mask(mask)) {
+                return Err(anyhow!(
+                    "Connection class {} has an invalid mask {}: only IP addresses, CIDR ranges and wildcard IP masks are supported",
+                    class.name,
+                    mask
+                ));
+            }
+        }
+
+        for (index, oper) in self.opers.iter().enumerate() {
+            if self.opers[..index]
+                .iter()
+                .any(|other| other.name == oper.name)
+            {
+                return Err(anyhow!("Duplicate oper block: {}", oper.name));
+            }
+            if !oper.password.starts_with("$2") {
+                return Err(anyhow!(
+                    "Password of oper {} is not a bcrypt hash",
+                    oper.name
+                ));
+            }
+            if !self
+                .oper_classes
+                .iter()
+                .any(|class| class.name == oper.class)
+            {
+                return Err(anyhow!(
+                    "Oper {} uses unknown class {}",
+                    oper.name,
+                    oper.class
+                ));
+            }
+        }
+
+        for (index, account) in self.accounts.iter().enumerate() {
+            if self.accounts[..index]
+                .iter()
+                .any(|other| other.name.eq_ignore_ascii_case(&account.name))
+            {
+                return Err(anyhow!("Duplicate account block: {}", account.name));
+            }
+            let name_valid = !account.name.is_empty()
+                && account
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !name_valid {
+                return Err(anyhow!("Invalid account name: {:?}", account.name));
+            }
+            if !account.password.starts_with("$2") {
+                return Err(anyhow!(
+                    "Password of account {} is not a bcrypt hash",
+                    account.name
+                ));
+            }
+            if let Some(vhost) = account
+                .vhost
+                .as_deref()
+                .filter(|vhost| !User::is_valid_host(vhost))
+            {
+                return Err(anyhow!(
+                    "Invalid vhost of account {}: {}",
+                    account.name,
+                    vhost
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the names of top-level keys whose values differ from `previous`.
+    pub fn changed_keys(&self, previous: &ServerConfig) -> Vec<String> {
+        let as_table = |config: &ServerConfig| match toml::Value::try_from(config) {
+            Ok(toml::Value::Table(table)) => table,
+            _ => Default::default(),
+        };
+        let (current, previous) = (as_table(self), as_table(previous));
+
+        let mut keys = current
+            .keys()
+            .chain(previous.keys())
+            .filter(|key| current.get(*key) != previous.get(*key))
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    pub fn find_oper(&self, name: &str) -> Option<&OperConfig> {
+        self.opers.iter().find(|oper| oper.name == name)
+    }
+
+    /// Account names are case-insensitive.
+    pub fn find_account(&self, name: &str) -> Option<&AccountConfig> {
+        self.accounts
+            .iter()
+            .find(|account| account.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Get the privileges granted to the operator with the given name.
+    pub fn oper_privileges(&self, oper_name: &str) -> &[Privilege] {
+        self.find_oper(oper_name)
+            .and_then(|oper| {
+                self.oper_classes
+                    .iter()
+                    .find(|class| class.name == oper.class)
+            })
+            .map(|class| &class.privileges[..])
+            .unwrap_or_default()
+    }
+
+    /// Get a connection class by name. Clients of a class that was removed
+    /// by a rehash fall back to the first class.
+    pub fn connect_class(&self, name: &str) -> &ConnectClassConfig {
+        self.classes
+            .iter()
+            .find(|class| class.name == name)
+            .unwrap_or(&self.classes[0])
+    }
+
+    /// Get the address of `dns_server`, using port 53 if none is given.
+    pub fn dns_server(&self) -> Option<SocketAddr> {
+        let server = self.dns_server.as_ref()?;
+        server
+            .parse::<SocketAddr>()
+            .ok()
+            .or_else(|| Some(SocketAddr::new(server.parse::<IpAddr>().ok()?, 53)))
+    }
+
     /// Get the (host, port) pair.
     pub fn addr(&self) -> (&str, u16) {
         (&self.host, self.port)
@@ -53,11 +795,40 @@ impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             name: String::from("Myriad Devnet"),
+            network: ServerConfig::default_network(),
             host: String::from("127.0.0.1"),
             port: 6667,
             motd: String::from("Don't worry, it only seems kinky the first time."),
+            motd_file: None,
+            ban_file: ServerConfig::default_ban_file(),
+            resolve_hostnames: ServerConfig::default_resolve_hostnames(),
+            ident_lookups: ServerConfig::default_ident_lookups(),
+            ident_port: ServerConfig::default_ident_port(),
+            lookup_timeout: ServerConfig::default_lookup_timeout(),
+            dns_server: None,
             feat_awaylen: ServerConfig::default_feat_awaylen(),
             feat_casemap: ServerConfig::default_feat_casemap(),
+            feat_nicklen: ServerConfig::default_feat_nicklen(),
+            feat_channellen: ServerConfig::default_feat_channellen(),
+            feat_topiclen: ServerConfig::default_feat_topiclen(),
+            feat_namelen: ServerConfig::default_feat_namelen(),
+            feat_kicklen: ServerConfig::default_feat_kicklen(),
+            feat_maxtargets: ServerConfig::default_feat_maxtargets(),
+            feat_chanlimit: ServerConfig::default_feat_chanlimit(),
+            feat_modes: ServerConfig::default_feat_modes(),
+            feat_monitor: ServerConfig::default_feat_monitor(),
+            admin: AdminConfig::default(),
+            flood: FloodConfig::default(),
+            cloak: CloakConfig {
+                enabled: true,
+                key: CloakConfig::random_key(),
+                prefix: CloakConfig::default_prefix(),
+            },
+            history: HistoryConfig::default(),
+            oper_classes: ServerConfig::default_oper_classes(),
+            classes: ServerConfig::default_classes(),
+            opers: vec![],
+            accounts: vec![],
         }
     }
 }
@@ -1,57 +1,25 @@
 use anyhow::{anyhow, Result};
-use std::fs::File;
-use std::io::{Read, Write};
 
+mod cloak;
 mod config;
+mod mask;
 mod models;
 mod numerics;
 mod server;
 
-use config::ServerConfig;
+use config::{ServerConfig, CONFIG_PATH};
 use server::Server;
 
 fn main() -> Result<()> {
-    let config = read_or_create_config()?;
-    Server::new(config).listen()
-}
-
-fn read_or_create_config() -> Result<ServerConfig> {
-    if std::path::Path::new("config.toml").exists() {
-        read_config()
-    } else {
-        create_config()
+    // Hash an operator password for use in the config
+    if std::env::args().nth(1).as_deref() == Some("mkpasswd") {
+        let password = std::env::args()
+            .nth(2)
+            .ok_or_else(|| anyhow!("Usage: myriad mkpasswd <password>"))?;
+        println!("{}", bcrypt::hash(password, bcrypt::DEFAULT_COST)?);
+        return Ok(());
     }
-}
 
-fn create_config() -> Result<ServerConfig> {
-    // Create default configuration
-    let config = ServerConfig::default();
-
-    // Serialize config to string
-    match toml::to_string(&config) {
-        Ok(config_text) => {
-            // Write to file
-            let mut file = File::create("config.toml")?;
-            file.write_all(config_text.as_ref())?;
-
-            // Return the configuration
-            Ok(config)
-        }
-        Err(_) => Err(anyhow!("Unable to serialize default config.")),
-    }
-}
-
-fn read_config() -> Result<ServerConfig> {
-    // Open config file
-    let mut config_file = File::open("config.toml")?;
-
-    // Read file into buffer
-    let mut config_text = String::new();
-    config_file.read_to_string(&mut config_text)?;
-
-    // Deserialize configuration
-    match toml::from_str(&config_text) {
-        Ok(config) => Ok(config),
-        Err(_) => Err(anyhow!("Unable to deserialize config.")),
-    }
+    let config = ServerConfig::read_or_create(CONFIG_PATH)?;
+    Server::new(config).listen()
 }
@@ -0,0 +1,152 @@
+use guard::guard;
+use std::net::IpAddr;
+
+/// Match `text` against an IRC wildcard mask, where `*` matches any
+/// sequence of characters and `?` matches exactly one character.
+/// The comparison is ASCII case-insensitive.
+pub fn matches(mask: &str, text: &str) -> bool {
+    let mask = mask.to_ascii_lowercase().chars().collect::<Vec<_>>();
+    let text = text.to_ascii_lowercase().chars().collect::<Vec<_>>();
+
+    let (mut m, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if m < mask.len() && (mask[m] == '?' || mask[m] == text[t]) {
+            m += 1;
+            t += 1;
+        } else if m < mask.len() && mask[m] == '*' {
+            backtrack = Some((m, t));
+            m += 1;
+        } else if let Some((star_m, star_t)) = backtrack {
+            // Let the last star consume one more character
+            m = star_m + 1;
+            t = star_t + 1;
+            backtrack = Some((star_m, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    mask[m..].iter().all(|c| *c == '*')
+}
+
+/// Match an IP address against a CIDR range (`192.0.2.0/24`),
+/// a single address or a wildcard mask.
+pub fn matches_ip(mask: &str, ip: IpAddr) -> bool {
+    guard!(let Some((network, prefix_len)) = mask.split_once('/') else {
+        return match mask.parse::<IpAddr>() {
+            Ok(mask_ip) => mask_ip == ip,
+            Err(_) => matches(mask, &ip.to_string()),
+        };
+    });
+    guard!(let (Ok(network), Ok(prefix_len)) = (network.parse::<IpAddr>(), prefix_len.parse::<u32>()) else {
+        return false;
+    });
+
+    let (network, ip, bits) = match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            (u32::from(network).into(), u32::from(ip).into(), 32)
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+        _ => return false,
+    };
+    if prefix_len > bits {
+        return false;
+    }
+    let shift = bits - prefix_len;
+    shift == bits || (network >> shift) == (ip >> shift)
+}
+
+/// Whether the mask is a valid IP address or CIDR range.
+pub fn is_ip_mask(mask: &str) -> bool {
+    match mask.split_once('/') {
+        Some((network, prefix_len)) => match (network.parse::<IpAddr>(), prefix_len.parse::<u32>())
+        {
+            (Ok(IpAddr::V4(_)), Ok(prefix_len)) => prefix_len <= 32,
+            (Ok(IpAddr::V6(_)), Ok(prefix_len)) => prefix_len <= 128,
+            _ => false,
+        },
+        None => mask.parse::<IpAddr>().is_ok(),
+    }
+}
+
+/// Whether the mask can only match IP addresses: an IP address,
+/// a CIDR range or a wildcard mask such as `192.0.2.*`.
+pub fn is_address_mask(mask: &str) -> bool {
+    if is_ip_mask(mask) {
+        return true;
+    }
+    let wildcard = |c: char| c == '*' || c == '?';
+    if mask.contains(':') {
+        mask.chars()
+            .all(|c| c.is_ascii_hexdigit() || c == ':' || c == '.' || wildcard(c))
+    } else {
+        !mask.is_empty()
+            && mask
+                .chars()
+                .all(|c| c.is_ascii_digit() || c == '.' || wildcard(c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("#rust*", "#rust-beginners"));
+        assert!(matches("#?ust", "#Rust"));
+        assert!(matches("*!*@*.example.com", "nick!user@host.EXAMPLE.com"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("#?ust", "#ust"));
+        assert!(!matches("a*b", "aXbY"));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn cidr_ranges() {
+        let ip = |text: &str| text.parse::<IpAddr>().unwrap();
+        assert!(matches_ip("192.0.2.0/24", ip("192.0.2.200")));
+        assert!(!matches_ip("192.0.2.0/24", ip("192.0.3.1")));
+        assert!(matches_ip("0.0.0.0/0", ip("203.0.113.7")));
+        assert!(matches_ip("192.0.2.1/32", ip("192.0.2.1")));
+        assert!(!matches_ip("192.0.2.1/32", ip("192.0.2.2")));
+        assert!(matches_ip("2001:db8::/32", ip("2001:db8:1::1")));
+        assert!(!matches_ip("2001:db8::/32", ip("2001:db9::1")));
+        assert!(matches_ip("::/0", ip("::1")));
+        assert!(!matches_ip("192.0.2.0/24", ip("::ffff:192.0.2.1")));
+        assert!(!matches_ip("192.0.2.0/33", ip("192.0.2.1")));
+        assert!(!matches_ip("192.0.2.0/x", ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn single_addresses_and_masks() {
+        let ip = |text: &str| text.parse::<IpAddr>().unwrap();
+        assert!(matches_ip("192.0.2.1", ip("192.0.2.1")));
+        assert!(matches_ip("2001:db8::1", ip("2001:0db8:0::1")));
+        assert!(matches_ip("192.0.2.*", ip("192.0.2.99")));
+        assert!(!matches_ip("192.0.3.*", ip("192.0.2.99")));
+    }
+
+    #[test]
+    fn ip_masks() {
+        assert!(is_ip_mask("192.0.2.0/24"));
+        assert!(is_ip_mask("2001:db8::/128"));
+        assert!(is_ip_mask("192.0.2.1"));
+        assert!(!is_ip_mask("192.0.2.0/33"));
+        assert!(!is_ip_mask("192.0.2.*"));
+    }
+
+    #[test]
+    fn address_masks() {
+        assert!(is_address_mask("*"));
+        assert!(is_address_mask("192.0.2.*"));
+        assert!(is_address_mask("192.0.2.0/24"));
+        assert!(is_address_mask("2001:db8::*"));
+        assert!(!is_address_mask(""));
+        assert!(!is_address_mask("*.example.com"));
+        assert!(!is_address_mask("*@192.0.2.1"));
+        assert!(!is_address_mask("192.0.2.0/33"));
+    }
+}
@@ -1,5 +1,18 @@
+mod ban;
+mod capability;
 mod channel;
+mod history;
+mod message;
+mod throttle;
 mod user;
 
-pub use channel::{Channel, ChannelMode, ChannelRef};
-pub use user::User;
+pub use ban::{Ban, BanKind};
+pub use capability::Capability;
+pub use channel::{Channel, ChannelMode, ChannelRef, ListFilter};
+pub use history::{format_time, HistoryEntry, HistoryRef, HistorySelector};
+pub use message::{
+    MessageKind, MessageTags, MultilineBatch, MultilineLine, ReplyKind, MAX_CLIENT_TAGS_LEN,
+    MAX_TAGS_LEN, MULTILINE_MAX_BYTES, MULTILINE_MAX_LINES,
+};
+pub use throttle::Throttle;
+pub use user::{Keepalive, User, UserMode};
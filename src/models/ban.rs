@@ -0,0 +1,159 @@
+use chrono::prelude::*;
+use guard::guard;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::mask;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BanKind {
+    /// `user@host` ban on this server.
+    Kline,
+    /// IP or CIDR ban, checked before registration.
+    Dline,
+    /// `user@host` ban on the whole network.
+    Gline,
+}
+
+impl BanKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Kline => "K-line",
+            Self::Dline => "D-line",
+            Self::Gline => "G-line",
+        }
+    }
+
+    /// Command adding bans of this kind.
+    pub fn command(&self) -> &'static str {
+        match self {
+            Self::Kline => "KLINE",
+            Self::Dline => "DLINE",
+            Self::Gline => "GLINE",
+        }
+    }
+
+    /// Letter used to list bans of this kind with STATS.
+    pub fn stats_letter(&self) -> char {
+        match self {
+            Self::Kline => 'k',
+            Self::Dline => 'd',
+            Self::Gline => 'g',
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ban {
+    pub kind: BanKind,
+    pub mask: String,
+    pub reason: String,
+    pub set_by: String,
+    /// Unix timestamp of when the ban was set.
+    pub set_at: i64,
+    /// Unix timestamp of when the ban expires, permanent if not set.
+    pub expires_at: Option<i64>,
+}
+
+impl Ban {
+    /// Create a ban set now, `None` if it would expire after the largest timestamp.
+    pub fn new(
+        kind: BanKind,
+        mask: String,
+        reason: String,
+        set_by: String,
+        duration: Option<i64>,
+    ) -> Option<Self> {
+        let set_at = Utc::now().timestamp();
+        let expires_at = match duration {
+            Some(seconds) => Some(set_at.checked_add(seconds)?),
+            None => None,
+        };
+        Some(Self {
+            kind,
+            mask,
+            reason,
+            set_by,
+            set_at,
+            expires_at,
+        })
+    }
+
+    /// Check a ban mask before it is added, returns a description of the problem.
+    pub fn mask_error(kind: BanKind, mask: &str) -> Option<&'static str> {
+        let is_wildcard = |text: &str| text.chars().all(|c| c == '*' || c == '?');
+        match kind {
+            BanKind::Dline if !mask::is_ip_mask(mask) => Some("Invalid IP address or CIDR range"),
+            BanKind::Dline if mask.ends_with("/0") => Some("Mask would ban everyone"),
+            BanKind::Kline | BanKind::Gline => match mask.split_once('@') {
+                None => Some("Mask must be of the form user@host"),
+                Some((_, host)) if is_wildcard(host) => Some("Mask would ban everyone"),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now().timestamp())
+    }
+
+    /// Test the ban against a connection. D-lines only look at the IP,
+    /// K-lines and G-lines at `user@ip` and `user@host` for the real
+    /// and cloaked host.
+    pub fn matches(&self, username: Option<&str>, hosts: &[&str], ip: IpAddr) -> bool {
+        if self.is_expired() {
+            return false;
+        }
+        match self.kind {
+            BanKind::Dline => mask::matches_ip(&self.mask, ip),
+            BanKind::Kline | BanKind::Gline => {
+                guard!(let Some(username) = username else { return false });
+                let (user_mask, host_mask) = self.mask.split_once('@').unwrap_or(("*", &self.mask));
+                mask::matches(user_mask, username)
+                    && (hosts.iter().any(|host| mask::matches(host_mask, host))
+                        || mask::matches_ip(host_mask, ip))
+            }
+        }
+    }
+
+    /// Seconds until the ban expires, if it isn't permanent.
+    pub fn remaining(&self) -> Option<i64> {
+        self.expires_at
+            .map(|expires_at| expires_at.saturating_sub(Utc::now().timestamp()).max(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(duration: Option<i64>) -> Option<Ban> {
+        Ban::new(
+            BanKind::Kline,
+            String::from("*@example.com"),
+            String::from("spam"),
+            String::from("oper"),
+            duration,
+        )
+    }
+
+    #[test]
+    fn expiry() {
+        let temporary = ban(Some(60)).unwrap();
+        assert_eq!(temporary.expires_at, Some(temporary.set_at + 60));
+        assert!(!temporary.is_expired());
+        assert!(ban(None).unwrap().expires_at.is_none());
+        assert!(ban(Some(i64::MAX)).is_none());
+    }
+
+    #[test]
+    fn remaining() {
+        let mut expired = ban(Some(60)).unwrap();
+        expired.expires_at = Some(i64::MIN);
+        assert!(expired.is_expired());
+        assert_eq!(expired.remaining(), Some(0));
+    }
+}
@@ -0,0 +1,86 @@
+use super::{MULTILINE_MAX_BYTES, MULTILINE_MAX_LINES};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    AccountNotify,
+    AccountTag,
+    AwayNotify,
+    Batch,
+    ChatHistory,
+    Chghost,
+    EchoMessage,
+    ExtendedJoin,
+    LabeledResponse,
+    MessageTags,
+    Multiline,
+    Sasl,
+    ServerTime,
+    Setname,
+    StandardReplies,
+}
+
+impl Capability {
+    /// All capabilities advertised in `CAP LS`.
+    pub const ALL: &'static [Capability] = &[
+        Capability::AccountNotify,
+        Capability::AccountTag,
+        Capability::AwayNotify,
+        Capability::Batch,
+        Capability::ChatHistory,
+        Capability::Chghost,
+        Capability::EchoMessage,
+        Capability::ExtendedJoin,
+        Capability::LabeledResponse,
+        Capability::MessageTags,
+        Capability::Multiline,
+        Capability::Sasl,
+        Capability::ServerTime,
+        Capability::Setname,
+        Capability::StandardReplies,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::AccountNotify => "account-notify",
+            Self::AccountTag => "account-tag",
+            Self::AwayNotify => "away-notify",
+            Self::Batch => "batch",
+            Self::ChatHistory => "draft/chathistory",
+            Self::Chghost => "chghost",
+            Self::EchoMessage => "echo-message",
+            Self::ExtendedJoin => "extended-join",
+            Self::LabeledResponse => "labeled-response",
+            Self::MessageTags => "message-tags",
+            Self::Multiline => "draft/multiline",
+            Self::Sasl => "sasl",
+            Self::ServerTime => "server-time",
+            Self::Setname => "setname",
+            Self::StandardReplies => "standard-replies",
+        }
+    }
+
+    /// Value advertised in `CAP LS 302`.
+    pub fn value(&self) -> Option<String> {
+        match self {
+            Self::Multiline => Some(format!(
+                "max-bytes={},max-lines={}",
+                MULTILINE_MAX_BYTES, MULTILINE_MAX_LINES
+            )),
+            Self::Sasl => Some(String::from("PLAIN")),
+            _ => None,
+        }
+    }
+
+    /// Capability a client needs to receive a message tag.
+    pub fn for_tag(key: &str) -> Self {
+        match key {
+            "time" => Self::ServerTime,
+            "account" => Self::AccountTag,
+            _ => Self::MessageTags,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|cap| cap.name() == name).copied()
+    }
+}
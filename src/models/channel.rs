@@ -1,15 +1,66 @@
+use chrono::prelude::*;
 use itertools::EitherOrBoth;
 
-#[derive(Debug)]
+use crate::mask;
+
+#[derive(Debug, PartialEq)]
 pub enum ChannelMode {
     Op { nickname: String },
     HalfOp { nickname: String },
+    Secret,
+    Private,
+}
+
+impl ChannelMode {
+    /// Letters of all flag-type modes known to the server.
+    pub fn flag_letters() -> String {
+        [Self::Private, Self::Secret]
+            .iter()
+            .flat_map(Self::letter)
+            .collect()
+    }
+
+    /// Letters of all membership prefix modes, along with their prefix symbol.
+    pub fn prefix_letters() -> Vec<(char, char)> {
+        vec![('o', '@')]
+    }
+
+    /// Get the mode letter of a flag-type mode.
+    pub fn letter(&self) -> Option<char> {
+        match self {
+            Self::Secret => Some('s'),
+            Self::Private => Some('p'),
+            _ => None,
+        }
+    }
+
+    /// Construct a prefix mode from its mode letter and the nickname of the member.
+    pub fn from_prefix_letter(letter: char, nickname: &str) -> Option<Self> {
+        match letter {
+            'o' => Some(Self::Op {
+                nickname: nickname.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Construct a flag-type mode from its mode letter.
+    pub fn from_letter(letter: char) -> Option<Self> {
+        match letter {
+            's' => Some(Self::Secret),
+            'p' => Some(Self::Private),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Channel {
     name: String,
     topic: String,
+    topic_set_by: Option<String>,
+    topic_set_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
     clients: Vec<u64>,
     modes: Vec<ChannelMode>,
 }
@@ -19,6 +70,9 @@ impl Channel {
         Self {
             name,
             topic: String::new(),
+            topic_set_by: None,
+            topic_set_at: None,
+            created_at: Utc::now(),
             clients: vec![],
             modes: vec![],
         }
@@ -32,6 +86,24 @@ impl Channel {
         &self.topic
     }
 
+    pub fn topic_set_by(&self) -> Option<&str> {
+        self.topic_set_by.as_deref()
+    }
+
+    pub fn topic_set_at(&self) -> Option<DateTime<Utc>> {
+        self.topic_set_at
+    }
+
+    pub fn set_topic(&mut self, topic: String, set_by: String) {
+        self.topic = topic;
+        self.topic_set_by = Some(set_by);
+        self.topic_set_at = Some(Utc::now());
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
     pub fn clients(&self) -> &[u64] {
         &self.clients[..]
     }
@@ -39,6 +111,72 @@ impl Channel {
     pub fn join_user(&mut self, client_id: u64) {
         self.clients.push(client_id);
     }
+
+    /// Remove a client from the channel, along with its operator status.
+    pub fn part_user(&mut self, client_id: u64, nickname: Option<&str>) {
+        self.clients.retain(|client| *client != client_id);
+        if let Some(nickname) = nickname {
+            self.remove_mode(&ChannelMode::Op {
+                nickname: nickname.to_string(),
+            });
+        }
+    }
+
+    pub fn has_mode(&self, mode: &ChannelMode) -> bool {
+        self.modes.contains(mode)
+    }
+
+    pub fn add_mode(&mut self, mode: ChannelMode) {
+        if !self.has_mode(&mode) {
+            self.modes.push(mode);
+        }
+    }
+
+    pub fn remove_mode(&mut self, mode: &ChannelMode) {
+        self.modes.retain(|m| m != mode);
+    }
+
+    /// Keep operator status across nickname changes.
+    pub fn rename_op(&mut self, prev_nickname: &str, nickname: &str) {
+        for mode in self.modes.iter_mut() {
+            if let ChannelMode::Op { nickname: op } = mode {
+                if op == prev_nickname {
+                    *op = nickname.to_string();
+                }
+            }
+        }
+    }
+
+    /// Membership prefix symbol of a nickname, as shown in NAMES.
+    pub fn prefix(&self, nickname: &str) -> &'static str {
+        if self.is_op(nickname) {
+            "@"
+        } else {
+            ""
+        }
+    }
+
+    pub fn is_op(&self, nickname: &str) -> bool {
+        self.modes.iter().any(|mode| match mode {
+            ChannelMode::Op { nickname: op } => op == nickname,
+            _ => false,
+        })
+    }
+
+    /// Whether the channel is hidden from users outside of it (`+s` or `+p`).
+    pub fn is_hidden(&self) -> bool {
+        self.has_mode(&ChannelMode::Secret) || self.has_mode(&ChannelMode::Private)
+    }
+
+    /// Get the flag-type modes as a mode string (e.g. `+ps`).
+    pub fn mode_string(&self) -> String {
+        let letters = self
+            .modes
+            .iter()
+            .flat_map(ChannelMode::letter)
+            .collect::<String>();
+        format!("+{}", letters)
+    }
 }
 
 #[derive(Debug)]
@@ -62,3 +200,130 @@ impl From<EitherOrBoth<&str, &str>> for ChannelRef {
         }
     }
 }
+
+/// ELIST filter for the LIST command.
+#[derive(Debug)]
+pub enum ListFilter {
+    /// `>n`: more than n users.
+    UsersAbove(usize),
+    /// `<n`: less than n users.
+    UsersBelow(usize),
+    /// `C>n`: created more than n minutes ago.
+    CreatedBefore(i64),
+    /// `C<n`: created less than n minutes ago.
+    CreatedWithin(i64),
+    /// `T>n`: topic changed more than n minutes ago.
+    TopicBefore(i64),
+    /// `T<n`: topic changed less than n minutes ago.
+    TopicWithin(i64),
+    /// Channel name mask.
+    Mask(String),
+    /// `!mask`: negated channel name mask.
+    NotMask(String),
+}
+
+impl ListFilter {
+    pub fn parse(filter: &str) -> Option<Self> {
+        let (kind, rest) = match filter.get(..1)? {
+            "C" | "T" => (filter.get(..1), &filter[1..]),
+            _ => (None, filter),
+        };
+        let number = || rest.get(1..)?.parse::<u32>().ok();
+
+        match (kind, rest.get(..1)?) {
+            (None, ">") => number().map(|n| Self::UsersAbove(n as usize)),
+            (None, "<") => number().map(|n| Self::UsersBelow(n as usize)),
+            (Some("C"), ">") => number().map(|n| Self::CreatedBefore(n.into())),
+            (Some("C"), "<") => number().map(|n| Self::CreatedWithin(n.into())),
+            (Some("T"), ">") => number().map(|n| Self::TopicBefore(n.into())),
+            (Some("T"), "<") => number().map(|n| Self::TopicWithin(n.into())),
+            (None, "!") => Some(Self::NotMask(rest[1..].to_string())),
+            _ => Some(Self::Mask(filter.to_string())),
+        }
+    }
+
+    /// Test the filter against a channel with the given visible user count.
+    /// Positive masks are handled separately, since any of them may match.
+    pub fn accepts(&self, channel: &Channel, user_count: usize) -> bool {
+        let minutes_since = |time: DateTime<Utc>| (Utc::now() - time).num_minutes();
+        match self {
+            Self::UsersAbove(n) => user_count > *n,
+            Self::UsersBelow(n) => user_count < *n,
+            Self::CreatedBefore(n) => minutes_since(channel.created_at()) > *n,
+            Self::CreatedWithin(n) => minutes_since(channel.created_at()) < *n,
+            Self::TopicBefore(n) => channel
+                .topic_set_at()
+                .is_some_and(|time| minutes_since(time) > *n),
+            Self::TopicWithin(n) => channel
+                .topic_set_at()
+                .is_some_and(|time| minutes_since(time) < *n),
+            Self::Mask(_) => true,
+            Self::NotMask(mask) => !mask::matches(mask, channel.name()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_filters() {
+        assert!(matches!(
+            ListFilter::parse(">5"),
+            Some(ListFilter::UsersAbove(5))
+        ));
+        assert!(matches!(
+            ListFilter::parse("<10"),
+            Some(ListFilter::UsersBelow(10))
+        ));
+        assert!(matches!(
+            ListFilter::parse("C>60"),
+            Some(ListFilter::CreatedBefore(60))
+        ));
+        assert!(matches!(
+            ListFilter::parse("C<1"),
+            Some(ListFilter::CreatedWithin(1))
+        ));
+        assert!(matches!(
+            ListFilter::parse("T>0"),
+            Some(ListFilter::TopicBefore(0))
+        ));
+        assert!(matches!(
+            ListFilter::parse("T<30"),
+            Some(ListFilter::TopicWithin(30))
+        ));
+        assert!(
+            matches!(ListFilter::parse("!#secret*"), Some(ListFilter::NotMask(mask)) if mask == "#secret*")
+        );
+        assert!(
+            matches!(ListFilter::parse("#rust*"), Some(ListFilter::Mask(mask)) if mask == "#rust*")
+        );
+    }
+
+    #[test]
+    fn invalid_list_filters() {
+        assert!(ListFilter::parse("").is_none());
+        assert!(ListFilter::parse(">").is_none());
+        assert!(ListFilter::parse(">-1").is_none());
+        assert!(ListFilter::parse("C>x").is_none());
+        assert!(ListFilter::parse(">99999999999").is_none());
+    }
+
+    #[test]
+    fn operator_prefixes() {
+        let mut channel = Channel::new(String::from("#test"));
+        channel.join_user(1);
+        channel.join_user(2);
+        channel.add_mode(ChannelMode::from_prefix_letter('o', "alice").unwrap());
+        assert_eq!(channel.prefix("alice"), "@");
+        assert_eq!(channel.prefix("bob"), "");
+        assert_eq!(channel.mode_string(), "+");
+
+        channel.rename_op("alice", "alicia");
+        assert_eq!(channel.prefix("alicia"), "@");
+        channel.part_user(1, Some("alicia"));
+        assert_eq!(channel.prefix("alicia"), "");
+        assert!(ChannelMode::from_prefix_letter('s', "alice").is_none());
+    }
+}
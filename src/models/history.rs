@@ -0,0 +1,155 @@
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{MessageKind, MessageTags, MultilineLine};
+
+/// A message kept in the history of a channel or private conversation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Channel or conversation the message belongs to, see `channel_key`
+    /// and `conversation_key`.
+    pub key: String,
+    pub msgid: String,
+    /// Milliseconds since the Unix epoch.
+    pub time: i64,
+    pub kind: MessageKind,
+    pub from_nickname: String,
+    /// Channel or nickname the message was sent to.
+    pub target: String,
+    /// Identities of the sender and target of a private message, see
+    /// `ServerQuery::history_identity`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub from_identity: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub target_identity: String,
+    pub text: String,
+    /// Lines of a `draft/multiline` message, `text` then holds all of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lines: Vec<MultilineLine>,
+    /// Client-only tags sent along with the message.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub client_tags: String,
+}
+
+impl HistoryEntry {
+    /// Create an entry for a relayed message, taking `time` and `msgid` from its tags.
+    pub fn new(
+        key: String,
+        kind: MessageKind,
+        from_nickname: &str,
+        target: &str,
+        text: &str,
+        lines: &[MultilineLine],
+        tags: &MessageTags,
+    ) -> Self {
+        let time = tags
+            .get("time")
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.timestamp_millis())
+            .unwrap_or_else(|| Utc::now().timestamp_millis());
+        Self {
+            key,
+            msgid: tags.get("msgid").unwrap_or_default().to_string(),
+            time,
+            kind,
+            from_nickname: from_nickname.to_string(),
+            target: target.to_string(),
+            from_identity: String::new(),
+            target_identity: String::new(),
+            text: text.to_string(),
+            lines: lines.to_vec(),
+            client_tags: tags.client_only().to_string(),
+        }
+    }
+
+    pub fn channel_key(channel: &str) -> String {
+        channel.to_ascii_lowercase()
+    }
+
+    /// Private conversations are keyed by both nicknames, so both sides
+    /// share the same history.
+    pub fn conversation_key(nickname: &str, other_nickname: &str) -> String {
+        let mut nicknames = [
+            nickname.to_ascii_lowercase(),
+            other_nickname.to_ascii_lowercase(),
+        ];
+        nicknames.sort();
+        nicknames.join(" ")
+    }
+
+    pub fn is_channel_key(key: &str) -> bool {
+        key.starts_with(['#', '&'])
+    }
+
+    /// Whether the message can be read by `nickname` with history `identity`.
+    /// Channel messages are checked by membership instead, private messages
+    /// only by the identity that sent or received them.
+    pub fn is_visible_to(&self, nickname: &str, identity: &str) -> bool {
+        if Self::is_channel_key(&self.key) {
+            return true;
+        }
+        let own_identity = if self.from_nickname.eq_ignore_ascii_case(nickname) {
+            &self.from_identity
+        } else {
+            &self.target_identity
+        };
+        !own_identity.is_empty() && own_identity == identity
+    }
+
+    /// The channel or nickname the history belongs to, as seen by `nickname`.
+    pub fn target_for(&self, nickname: &str) -> &str {
+        if Self::is_channel_key(&self.key) || self.from_nickname.eq_ignore_ascii_case(nickname) {
+            &self.target
+        } else {
+            &self.from_nickname
+        }
+    }
+
+    /// Tags of the message as relayed, including `time` and `msgid`.
+    pub fn tags(&self) -> MessageTags {
+        let mut tags = MessageTags::parse(&self.client_tags);
+        tags.insert("time", &format_time(self.time));
+        tags.insert("msgid", &self.msgid);
+        tags
+    }
+}
+
+/// Format milliseconds since the Unix epoch as used by the `time` tag.
+pub fn format_time(millis: i64) -> String {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .unwrap_or_default()
+}
+
+/// Reference to a point in a history.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistoryRef {
+    /// Milliseconds since the Unix epoch.
+    Timestamp(i64),
+    MsgId(String),
+}
+
+impl HistoryRef {
+    /// Parse `timestamp=<RFC 3339>` or `msgid=<id>`.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.split_once('=')? {
+            ("timestamp", timestamp) => DateTime::parse_from_rfc3339(timestamp)
+                .ok()
+                .map(|time| Self::Timestamp(time.timestamp_millis())),
+            ("msgid", msgid) if !msgid.is_empty() => Some(Self::MsgId(msgid.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Messages requested by a CHATHISTORY subcommand, bounds are exclusive.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistorySelector {
+    /// The latest messages, optionally only those after a reference.
+    Latest(Option<HistoryRef>),
+    Before(HistoryRef),
+    After(HistoryRef),
+    Around(HistoryRef),
+    Between(HistoryRef, HistoryRef),
+}
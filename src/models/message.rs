@@ -0,0 +1,326 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Maximum size of the tags of a line, including the `@` and the trailing space.
+pub const MAX_TAGS_LEN: usize = 8191;
+
+/// Maximum size of the client-only tags a client may send.
+pub const MAX_CLIENT_TAGS_LEN: usize = 4094;
+
+/// Maximum size of the text of a `draft/multiline` batch, including line breaks.
+pub const MULTILINE_MAX_BYTES: usize = 4096;
+
+/// Maximum number of messages in a `draft/multiline` batch.
+pub const MULTILINE_MAX_LINES: usize = 24;
+
+/// Commands relaying text (or just tags) between clients.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Privmsg,
+    Notice,
+    Tagmsg,
+}
+
+impl MessageKind {
+    pub fn command(&self) -> &'static str {
+        match self {
+            Self::Privmsg => "PRIVMSG",
+            Self::Notice => "NOTICE",
+            Self::Tagmsg => "TAGMSG",
+        }
+    }
+
+    pub fn from_command(command: &str) -> Option<Self> {
+        match command {
+            "PRIVMSG" => Some(Self::Privmsg),
+            "NOTICE" => Some(Self::Notice),
+            "TAGMSG" => Some(Self::Tagmsg),
+            _ => None,
+        }
+    }
+}
+
+/// A line of a multiline message. `concat` lines continue the previous
+/// line instead of starting a new one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MultilineLine {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub concat: bool,
+}
+
+impl MultilineLine {
+    /// The text of a multiline message, lines separated by line breaks.
+    pub fn join(lines: &[MultilineLine]) -> String {
+        let mut text = String::new();
+        for (index, line) in lines.iter().enumerate() {
+            if index > 0 && !line.concat {
+                text.push('\n');
+            }
+            text.push_str(&line.text);
+        }
+        text
+    }
+
+    /// The lines shown to clients without `draft/multiline`, skipping blank
+    /// ones. Concatenated lines are sent separately, joined they could exceed
+    /// the line length limit.
+    pub fn fallback(lines: &[MultilineLine]) -> Vec<String> {
+        lines
+            .iter()
+            .filter(|line| !line.text.is_empty())
+            .map(|line| line.text.clone())
+            .collect()
+    }
+}
+
+/// A `draft/multiline` batch a client is still sending.
+#[derive(Debug, Clone)]
+pub struct MultilineBatch {
+    pub reference: String,
+    pub target: String,
+    /// Command of the messages, all messages of a batch must use the same.
+    pub kind: Option<MessageKind>,
+    pub lines: Vec<MultilineLine>,
+    /// Client-only tags of the line starting the batch.
+    pub tags: MessageTags,
+    /// Set once the batch was rejected, its remaining lines are dropped.
+    pub rejected: bool,
+}
+
+/// Kinds of IRCv3 standard replies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplyKind {
+    Fail,
+    Warn,
+    Note,
+}
+
+impl ReplyKind {
+    pub fn command(&self) -> &'static str {
+        match self {
+            Self::Fail => "FAIL",
+            Self::Warn => "WARN",
+            Self::Note => "NOTE",
+        }
+    }
+}
+
+/// IRCv3 message tags. Values are kept escaped, as they are relayed unchanged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageTags {
+    tags: Vec<(String, String)>,
+}
+
+impl MessageTags {
+    /// Split a raw line into its tags (without the `@`) and the rest of the line.
+    pub fn split(line: &str) -> (Option<&str>, &str) {
+        if !line.starts_with('@') {
+            return (None, line);
+        }
+        match line.split_once(' ') {
+            Some((tags, rest)) => (Some(&tags[1..]), rest.trim_start_matches(' ')),
+            None => (Some(&line[1..]), ""),
+        }
+    }
+
+    /// Parse the tags of a line, dropping tags with invalid keys.
+    /// Later values of a key replace earlier ones.
+    pub fn parse(raw: &str) -> Self {
+        let mut tags = Self::default();
+        for tag in raw.split(';').filter(|tag| !tag.is_empty()) {
+            let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+            if Self::is_valid_key(key) {
+                tags.insert(key, value);
+            }
+        }
+        tags
+    }
+
+    /// Keys are an optional `+` (client-only), an optional vendor
+    /// hostname followed by `/`, and a name of letters, digits and hyphens.
+    pub fn is_valid_key(key: &str) -> bool {
+        let key = key.strip_prefix('+').unwrap_or(key);
+        let (vendor, name) = match key.rsplit_once('/') {
+            Some((vendor, name)) => (Some(vendor), name),
+            None => (None, key),
+        };
+        let vendor_valid = vendor.is_none_or(|vendor| {
+            !vendor.is_empty()
+                && vendor
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        });
+        vendor_valid
+            && !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        match self.tags.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.tags.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Insert all tags of `other`, replacing existing values.
+    pub fn extend(&mut self, other: &MessageTags) {
+        for (key, value) in other.tags.iter() {
+            self.insert(key, value);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    /// Only the tags whose key is accepted by `keep`.
+    pub fn filtered(&self, keep: impl Fn(&str) -> bool) -> Self {
+        Self {
+            tags: self
+                .tags
+                .iter()
+                .filter(|(key, _)| keep(key))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Only the client-only tags, whose keys start with `+`.
+    pub fn client_only(&self) -> Self {
+        self.filtered(|key| key.starts_with('+'))
+    }
+
+    /// Size of the tags as sent, without the `@` and the trailing space.
+    pub fn len_raw(&self) -> usize {
+        let separators = self.tags.len().saturating_sub(1);
+        let tags = self
+            .tags
+            .iter()
+            .map(|(key, value)| key.len() + if value.is_empty() { 0 } else { value.len() + 1 })
+            .sum::<usize>();
+        tags + separators
+    }
+}
+
+impl fmt::Display for MessageTags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (key, value)) in self.tags.iter().enumerate() {
+            if index > 0 {
+                f.write_str(";")?;
+            }
+            f.write_str(key)?;
+            if !value.is_empty() {
+                write!(f, "={}", value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        assert_eq!(
+            MessageTags::split("@a=b;c PRIVMSG #x :hi"),
+            (Some("a=b;c"), "PRIVMSG #x :hi")
+        );
+        assert_eq!(MessageTags::split("@a=b   PING x"), (Some("a=b"), "PING x"));
+        assert_eq!(MessageTags::split("@a=b"), (Some("a=b"), ""));
+        assert_eq!(MessageTags::split("PING x"), (None, "PING x"));
+    }
+
+    #[test]
+    fn valid_keys() {
+        assert!(MessageTags::is_valid_key("msgid"));
+        assert!(MessageTags::is_valid_key("+typing"));
+        assert!(MessageTags::is_valid_key("+draft/reply"));
+        assert!(MessageTags::is_valid_key("example.com/some-tag"));
+        assert!(!MessageTags::is_valid_key(""));
+        assert!(!MessageTags::is_valid_key("+"));
+        assert!(!MessageTags::is_valid_key("/tag"));
+        assert!(!MessageTags::is_valid_key("vendor/"));
+        assert!(!MessageTags::is_valid_key("under_score"));
+        assert!(!MessageTags::is_valid_key("ex ample.com/tag"));
+    }
+
+    #[test]
+    fn parse() {
+        let tags = MessageTags::parse("a=1;;+b;bad key=x;a=2;c=");
+        assert_eq!(tags.get("a"), Some("2"));
+        assert_eq!(tags.get("+b"), Some(""));
+        assert_eq!(tags.get("c"), Some(""));
+        assert_eq!(tags.get("bad key"), None);
+        assert_eq!(tags.to_string(), "a=2;+b;c");
+        assert!(MessageTags::parse("").is_empty());
+    }
+
+    #[test]
+    fn escaped_values_are_kept() {
+        let raw = r"+draft/reply=a\:b;+text=one\stwo\\three\r\n";
+        let tags = MessageTags::parse(raw);
+        assert_eq!(tags.get("+text"), Some(r"one\stwo\\three\r\n"));
+        assert_eq!(tags.to_string(), raw);
+        assert_eq!(tags.len_raw(), raw.len());
+    }
+
+    #[test]
+    fn client_only() {
+        let tags = MessageTags::parse("time=x;+typing=active;msgid=1;+draft/react=y");
+        let client_tags = "+typing=active;+draft/react=y";
+        assert_eq!(tags.client_only().to_string(), client_tags);
+        assert_eq!(tags.client_only().len_raw(), client_tags.len());
+    }
+
+    fn lines(lines: &[(&str, bool)]) -> Vec<MultilineLine> {
+        lines
+            .iter()
+            .map(|(text, concat)| MultilineLine {
+                text: text.to_string(),
+                concat: *concat,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn multiline_join() {
+        let lines = lines(&[
+            ("hello ", false),
+            ("world", true),
+            ("", false),
+            ("bye", false),
+        ]);
+        assert_eq!(MultilineLine::join(&lines), "hello world\n\nbye");
+    }
+
+    #[test]
+    fn multiline_fallback() {
+        let lines = lines(&[
+            ("hello ", false),
+            ("world", true),
+            ("", false),
+            ("bye", false),
+        ]);
+        assert_eq!(MultilineLine::fallback(&lines), ["hello ", "world", "bye"]);
+    }
+
+    #[test]
+    fn multiline_fallback_keeps_long_messages_split() {
+        let line = "a".repeat(400);
+        let concat = (0..10).map(|_| (line.as_str(), true)).collect::<Vec<_>>();
+        let fallback = MultilineLine::fallback(&lines(&concat));
+        assert_eq!(fallback.len(), 10);
+        assert!(fallback.iter().all(|text| text.len() == 400));
+    }
+}
@@ -0,0 +1,271 @@
+use std::{collections::VecDeque, mem, time::Instant};
+
+use super::{MessageKind, MessageTags, MULTILINE_MAX_LINES};
+use crate::config::FloodConfig;
+
+/// Token bucket used to delay commands of flooding clients (fakelag).
+#[derive(Debug)]
+pub struct Throttle {
+    tokens: f64,
+    last_refill: Instant,
+    queue: VecDeque<String>,
+    /// Reference of the client's open multiline batch.
+    batch: Option<String>,
+    /// Number of lines of the open batch and their penalty, which is
+    /// charged once the batch ends.
+    batch_lines: usize,
+    batch_penalty: u32,
+}
+
+impl Throttle {
+    pub fn new(config: &FloodConfig) -> Self {
+        Self {
+            tokens: config.burst.into(),
+            last_refill: Instant::now(),
+            queue: VecDeque::new(),
+            batch: None,
+            batch_lines: 0,
+            batch_penalty: 0,
+        }
+    }
+
+    fn refill(&mut self, config: &FloodConfig) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(config.rate)).min(config.burst.into());
+        self.last_refill = now;
+    }
+
+    /// Queue a line for processing.
+    pub fn push(&mut self, line: String) {
+        self.queue.push_back(line);
+    }
+
+    /// Take the next queued line if the bucket holds enough tokens for it.
+    /// `batch` is the reference of the client's open multiline batch, its
+    /// lines are charged together with the line closing it.
+    pub fn pop_ready(&mut self, config: &FloodConfig, batch: Option<&str>) -> Option<String> {
+        self.refill(config);
+
+        // Batches that ended without a closing line (e.g. on failure) are charged right away
+        if self.batch.as_deref() != batch {
+            self.tokens -= f64::from(mem::take(&mut self.batch_penalty));
+            self.batch = batch.map(ToString::to_string);
+            self.batch_lines = 0;
+        }
+
+        let line = self.queue.front()?;
+        let mut penalty = config.penalty(line);
+        let mut ends_batch = false;
+        if let Some(reference) = batch {
+            let (tags, command) = MessageTags::split(line);
+            let mut words = command
+                .split_whitespace()
+                .skip_while(|word| word.starts_with(':'));
+            let command = words.next().unwrap_or_default().to_ascii_uppercase();
+
+            // Only messages are part of a multiline batch
+            let in_batch = matches!(
+                MessageKind::from_command(&command),
+                Some(MessageKind::Privmsg | MessageKind::Notice)
+            ) && tags
+                .is_some_and(|tags| MessageTags::parse(tags).get("batch") == Some(reference));
+            if in_batch && self.batch_lines < MULTILINE_MAX_LINES {
+                self.batch_lines += 1;
+                self.batch_penalty = self.batch_penalty.saturating_add(penalty);
+                return self.queue.pop_front();
+            }
+
+            ends_batch = command == "BATCH" && words.next() == Some(&format!("-{}", reference));
+            if ends_batch {
+                penalty = penalty.saturating_add(self.batch_penalty);
+            }
+        }
+
+        // Penalties above the burst only have to wait for a full bucket,
+        // the tokens then go negative
+        if self.tokens < f64::from(penalty.min(config.burst)) {
+            return None;
+        }
+        self.tokens -= f64::from(penalty);
+        if ends_batch {
+            self.batch_penalty = 0;
+        }
+        self.queue.pop_front()
+    }
+
+    /// Number of delayed lines.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    fn config(burst: u32, rate: u32) -> FloodConfig {
+        FloodConfig {
+            burst,
+            rate,
+            ..FloodConfig::default()
+        }
+    }
+
+    #[test]
+    fn burst() {
+        let config = config(4, 1);
+        let mut throttle = Throttle::new(&config);
+        for _ in 0..3 {
+            throttle.push(String::from("PRIVMSG #a :hi"));
+        }
+        assert!(throttle.pop_ready(&config, None).is_some());
+        assert!(throttle.pop_ready(&config, None).is_some());
+        assert!(throttle.pop_ready(&config, None).is_none());
+        assert_eq!(throttle.queued(), 1);
+    }
+
+    #[test]
+    fn free_commands() {
+        let config = config(1, 1);
+        let mut throttle = Throttle::new(&config);
+        throttle.push(String::from("NICK a"));
+        throttle.push(String::from("PONG :x"));
+        throttle.push(String::from("PONG :y"));
+        assert!(throttle.pop_ready(&config, None).is_some());
+        assert_eq!(
+            throttle.pop_ready(&config, None).as_deref(),
+            Some("PONG :x")
+        );
+        assert_eq!(
+            throttle.pop_ready(&config, None).as_deref(),
+            Some("PONG :y")
+        );
+        assert!(throttle.pop_ready(&config, None).is_none());
+    }
+
+    #[test]
+    fn refill() {
+        let config = config(2, 1000);
+        let mut throttle = Throttle::new(&config);
+        for _ in 0..3 {
+            throttle.push(String::from("JOIN #a"));
+        }
+        assert!(throttle.pop_ready(&config, None).is_some());
+        thread::sleep(Duration::from_millis(20));
+        assert!(throttle.pop_ready(&config, None).is_some());
+        thread::sleep(Duration::from_millis(20));
+        assert!(throttle.pop_ready(&config, None).is_some());
+        assert_eq!(throttle.queued(), 0);
+    }
+
+    #[test]
+    fn tokens_are_capped_at_burst() {
+        let config = config(2, 1000);
+        let mut throttle = Throttle::new(&config);
+        thread::sleep(Duration::from_millis(20));
+        for _ in 0..2 {
+            throttle.push(String::from("PRIVMSG #a :hi"));
+        }
+        assert!(throttle.pop_ready(&config, None).is_some());
+        assert!(throttle.pop_ready(&config, None).is_none());
+    }
+
+    #[test]
+    fn batches_are_charged_when_they_end() {
+        let config = config(5, 1);
+        let mut throttle = Throttle::new(&config);
+        for _ in 0..4 {
+            throttle.push(String::from("@batch=a PRIVMSG #a :hi"));
+        }
+        throttle.push(String::from("BATCH -a"));
+        throttle.push(String::from("PRIVMSG #a :hi"));
+
+        for _ in 0..4 {
+            assert!(throttle.pop_ready(&config, Some("a")).is_some());
+        }
+        // The closing line pays for the four lines and itself
+        assert_eq!(
+            throttle.pop_ready(&config, Some("a")).as_deref(),
+            Some("BATCH -a")
+        );
+        assert!(throttle.pop_ready(&config, None).is_none());
+        assert!(throttle.tokens < -3.9);
+    }
+
+    #[test]
+    fn lines_of_other_batches_are_charged() {
+        let config = config(4, 1);
+        let mut throttle = Throttle::new(&config);
+        for _ in 0..3 {
+            throttle.push(String::from("@batch=b PRIVMSG #a :hi"));
+        }
+        assert!(throttle.pop_ready(&config, None).is_some());
+        assert!(throttle.pop_ready(&config, Some("a")).is_some());
+        assert!(throttle.pop_ready(&config, Some("a")).is_none());
+    }
+
+    #[test]
+    fn only_messages_of_the_batch_are_deferred() {
+        let config = config(10, 1);
+        let mut throttle = Throttle::new(&config);
+        for _ in 0..2 {
+            throttle.push(String::from("@batch=a OPER root secret"));
+        }
+        assert!(throttle.pop_ready(&config, Some("a")).is_some());
+        assert!(throttle.pop_ready(&config, Some("a")).is_none());
+        assert_eq!(throttle.batch_penalty, 0);
+    }
+
+    #[test]
+    fn batch_lines_are_limited() {
+        let config = config(2, 1);
+        let mut throttle = Throttle::new(&config);
+        for _ in 0..MULTILINE_MAX_LINES + 2 {
+            throttle.push(String::from("@batch=a PRIVMSG #a :hi"));
+        }
+        for _ in 0..MULTILINE_MAX_LINES + 1 {
+            assert!(throttle.pop_ready(&config, Some("a")).is_some());
+        }
+        assert!(throttle.pop_ready(&config, Some("a")).is_none());
+    }
+
+    #[test]
+    fn batches_ending_without_closing_line_are_charged() {
+        let config = config(3, 1);
+        let mut throttle = Throttle::new(&config);
+        for _ in 0..2 {
+            throttle.push(String::from("@batch=a PRIVMSG #a :hi"));
+        }
+        throttle.push(String::from("PRIVMSG #a :hi"));
+        assert!(throttle.pop_ready(&config, Some("a")).is_some());
+        assert!(throttle.pop_ready(&config, Some("a")).is_some());
+        assert!(throttle.pop_ready(&config, None).is_none());
+    }
+
+    #[test]
+    fn oper_uses_the_whole_burst() {
+        let config = FloodConfig::default();
+        let mut throttle = Throttle::new(&config);
+        for _ in 0..2 {
+            throttle.push(String::from("OPER root secret"));
+        }
+        assert!(throttle.pop_ready(&config, None).is_some());
+        assert!(throttle.pop_ready(&config, None).is_none());
+    }
+
+    #[test]
+    fn sasl_attempts_are_limited() {
+        let config = FloodConfig::default();
+        let mut throttle = Throttle::new(&config);
+        for _ in 0..2 {
+            throttle.push(String::from("AUTHENTICATE PLAIN"));
+            throttle.push(String::from("AUTHENTICATE AGFsaWNlAHNlY3JldA=="));
+        }
+        for _ in 0..3 {
+            assert!(throttle.pop_ready(&config, None).is_some());
+        }
+        assert!(throttle.pop_ready(&config, None).is_none());
+    }
+}
@@ -1,24 +1,274 @@
-use std::net::TcpStream;
+use std::{
+    net::{IpAddr, TcpStream},
+    time::{Duration, Instant},
+};
+
+use irc_rust::Message;
+
+use super::{Capability, MultilineBatch, Throttle};
+use crate::config::{FloodConfig, Privilege};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserMode {
+    Invisible,
+    Operator,
+    ServerNotices,
+    Wallops,
+    Cloaked,
+}
+
+impl UserMode {
+    /// All user modes known to the server.
+    pub const ALL: &'static [UserMode] = &[
+        UserMode::Invisible,
+        UserMode::Operator,
+        UserMode::ServerNotices,
+        UserMode::Wallops,
+        UserMode::Cloaked,
+    ];
+
+    pub fn letter(&self) -> char {
+        match self {
+            Self::Invisible => 'i',
+            Self::Operator => 'o',
+            Self::ServerNotices => 's',
+            Self::Wallops => 'w',
+            Self::Cloaked => 'x',
+        }
+    }
+
+    /// Operator privilege required to set the mode.
+    pub fn required_privilege(&self) -> Option<Privilege> {
+        match self {
+            Self::ServerNotices => Some(Privilege::ServerNotices),
+            _ => None,
+        }
+    }
+
+    /// Whether users may set the mode on themselves using MODE.
+    pub fn is_user_settable(&self) -> bool {
+        !matches!(self, Self::Operator)
+    }
+
+    pub fn from_letter(letter: char) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .find(|mode| mode.letter() == letter)
+            .copied()
+    }
+}
+
+/// Outcome of the keepalive check of a client, see `User::keepalive`.
+#[derive(Debug, PartialEq)]
+pub enum Keepalive {
+    Alive,
+    Ping,
+    /// Disconnect the client with the given reason.
+    Timeout(String),
+}
 
 #[derive(Debug)]
 pub struct User {
     pub stream: TcpStream,
     pub client_id: u64,
+    pub ip: IpAddr,
     pub host: String,
+    /// Host shown to other users while user mode `+x` is set.
+    pub cloaked_host: Option<String>,
+    /// Host assigned by an operator or account, shown instead of the real or cloaked host.
+    pub vhost: Option<String>,
+    /// Name of the connection class.
+    pub class: String,
     pub nickname: Option<String>,
     pub username: Option<String>,
+    /// Username reported by the client's identd.
+    pub ident: Option<String>,
     pub realname: Option<String>,
+    pub away: Option<String>,
+    /// Lowercase nicknames watched using MONITOR.
+    pub monitor: Vec<String>,
+    pub modes: Vec<UserMode>,
+    pub oper_name: Option<String>,
+    /// Account the user logged into using SASL.
+    pub account: Option<String>,
+    /// Base64 data received so far while SASL authentication is in progress.
+    pub sasl_buffer: Option<String>,
+    /// `draft/multiline` batch the client is sending.
+    pub multiline: Option<MultilineBatch>,
+    pub capabilities: Vec<Capability>,
+    pub cap_negotiating: bool,
+    pub registered: bool,
+    /// Set when writes to the client time out, see `sendq` of the connection class.
+    pub sendq_exceeded: bool,
+    pub throttle: Throttle,
+    pub connected_at: Instant,
+    /// Time the client last sent data.
+    pub last_activity: Instant,
+    /// Time of the keepalive PING that hasn't been answered yet.
+    pub ping_sent_at: Option<Instant>,
+    /// Label of the command being processed, along with the output of
+    /// actions dispatched for the client by client ID meanwhile.
+    pub labeled_responses: Option<(String, Vec<Message>)>,
 }
 
 impl User {
-    pub fn new(stream: TcpStream, client_id: u64, host: String) -> Self {
+    pub fn new(
+        stream: TcpStream,
+        client_id: u64,
+        ip: IpAddr,
+        class: String,
+        flood: &FloodConfig,
+    ) -> Self {
         Self {
             stream,
             client_id,
-            host,
+            ip,
+            host: ip.to_string(),
+            cloaked_host: None,
+            vhost: None,
+            class,
             nickname: None,
             username: None,
+            ident: None,
             realname: None,
+            away: None,
+            monitor: vec![],
+            modes: vec![],
+            oper_name: None,
+            account: None,
+            sasl_buffer: None,
+            multiline: None,
+            capabilities: vec![],
+            cap_negotiating: false,
+            registered: false,
+            sendq_exceeded: false,
+            throttle: Throttle::new(flood),
+            connected_at: Instant::now(),
+            last_activity: Instant::now(),
+            ping_sent_at: None,
+            labeled_responses: None,
+        }
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    pub fn has_mode(&self, mode: UserMode) -> bool {
+        self.modes.contains(&mode)
+    }
+
+    /// Registered clients are pinged once they were idle for `ping_frequency`, and
+    /// time out if the PING isn't answered within another `ping_frequency`.
+    /// Unregistered clients time out after `registration_timeout`.
+    pub fn keepalive(&self, ping_frequency: Duration, registration_timeout: Duration) -> Keepalive {
+        let idle = self.last_activity.elapsed();
+        if !self.registered {
+            if self.connected_at.elapsed() >= registration_timeout {
+                return Keepalive::Timeout(String::from("Registration timed out"));
+            }
+        } else if let Some(ping_sent_at) = self.ping_sent_at {
+            if ping_sent_at.elapsed() >= ping_frequency {
+                return Keepalive::Timeout(format!("Ping timeout: {} seconds", idle.as_secs()));
+            }
+        } else if idle >= ping_frequency {
+            return Keepalive::Ping;
+        }
+        Keepalive::Alive
+    }
+
+    /// Get the user modes as a mode string (e.g. `+i`).
+    pub fn mode_string(&self) -> String {
+        let letters = self.modes.iter().map(UserMode::letter).collect::<String>();
+        format!("+{}", letters)
+    }
+
+    /// Host shown to other users.
+    pub fn visible_host(&self) -> &str {
+        match (&self.vhost, &self.cloaked_host) {
+            (Some(vhost), _) => vhost,
+            (_, Some(cloaked_host)) if self.has_mode(UserMode::Cloaked) => cloaked_host,
+            _ => &self.host,
         }
     }
+
+    /// Real, cloaked and virtual host, for matching bans.
+    pub fn hosts(&self) -> Vec<&str> {
+        std::iter::once(self.host.as_str())
+            .chain(self.cloaked_host.as_deref())
+            .chain(self.vhost.as_deref())
+            .collect()
+    }
+
+    /// Hosts assigned using CHGHOST or account vhosts may contain letters,
+    /// digits, `.`, `-`, `:` and `/`, and must not start with `:`.
+    pub fn is_valid_host(host: &str) -> bool {
+        !host.is_empty()
+            && host.len() <= 63
+            && !host.starts_with(':')
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || ".-:/".contains(c))
+    }
+
+    /// Nickname for use in numeric replies, `*` if none has been set yet.
+    pub fn nickname_or_star(&self) -> String {
+        self.nickname.clone().unwrap_or_else(|| String::from("*"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn user() -> User {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let ip = stream.peer_addr().unwrap().ip();
+        User::new(
+            stream,
+            1,
+            ip,
+            String::from("default"),
+            &FloodConfig::default(),
+        )
+    }
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn registration_timeout() {
+        let mut user = user();
+        assert_eq!(user.keepalive(SECOND, SECOND), Keepalive::Alive);
+        user.connected_at -= 2 * SECOND;
+        assert_eq!(
+            user.keepalive(SECOND, SECOND),
+            Keepalive::Timeout(String::from("Registration timed out"))
+        );
+    }
+
+    #[test]
+    fn ping_when_idle() {
+        let mut user = user();
+        user.registered = true;
+        user.connected_at -= 10 * SECOND;
+        assert_eq!(user.keepalive(SECOND, SECOND), Keepalive::Alive);
+        user.last_activity -= 2 * SECOND;
+        assert_eq!(user.keepalive(SECOND, SECOND), Keepalive::Ping);
+    }
+
+    #[test]
+    fn ping_timeout() {
+        let mut user = user();
+        user.registered = true;
+        user.last_activity -= 3 * SECOND;
+        user.ping_sent_at = Some(Instant::now());
+        assert_eq!(user.keepalive(2 * SECOND, SECOND), Keepalive::Alive);
+        user.ping_sent_at = Some(Instant::now() - 2 * SECOND);
+        assert_eq!(
+            user.keepalive(2 * SECOND, SECOND),
+            Keepalive::Timeout(String::from("Ping timeout: 3 seconds"))
+        );
+    }
 }
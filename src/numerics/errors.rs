@@ -8,10 +8,44 @@ macro_rules! define_numerics {
 }
 
 define_numerics! {
+    // Targets
+    "401" => ERR_NOSUCHNICK,
+    "403" => ERR_NOSUCHCHANNEL,
+    "405" => ERR_TOOMANYCHANNELS,
+    "407" => ERR_TOOMANYTARGETS,
+    // CAP
+    "410" => ERR_INVALIDCAPCMD,
+    // Message tags
+    "417" => ERR_INPUTTOOLONG,
     // NICK
     "431" => ERR_NONICKNAMEGIVEN,
+    "432" => ERR_ERRONEUSNICKNAME,
     "433" => ERR_NICKNAMEINUSE,
     // USER
     "461" => ERR_NEEDMOREPARAMS,
     "462" => ERR_ALREADYREGISTRED,
+    // Channels
+    "441" => ERR_USERNOTINCHANNEL,
+    "442" => ERR_NOTONCHANNEL,
+    "472" => ERR_UNKNOWNMODE,
+    "476" => ERR_BADCHANMASK,
+    "482" => ERR_CHANOPRIVSNEEDED,
+    // OPER
+    "464" => ERR_PASSWDMISMATCH,
+    "465" => ERR_YOUREBANNEDCREEP,
+    "481" => ERR_NOPRIVILEGES,
+    "491" => ERR_NOOPERHOST,
+    // User modes
+    "501" => ERR_UMODEUNKNOWNFLAG,
+    "502" => ERR_USERSDONTMATCH,
+    // MOTD
+    "422" => ERR_NOMOTD,
+    // Registration
+    "451" => ERR_NOTREGISTERED,
+    // SASL
+    "904" => ERR_SASLFAIL,
+    "905" => ERR_SASLTOOLONG,
+    "906" => ERR_SASLABORTED,
+    // MONITOR
+    "734" => ERR_MONLISTFULL,
 }
@@ -14,14 +14,78 @@ define_numerics! {
     "003" => RPL_CREATED,
     "004" => RPL_MYINFO,
     "005" => RPL_ISUPPORT,
+    // User modes
+    "221" => RPL_UMODEIS,
+    "396" => RPL_HOSTHIDDEN,
     // LUSERS
     "251" => RPL_LUSERCLIENT,
     "252" => RPL_LUSEROP,
+    "253" => RPL_LUSERUNKNOWN,
+    "254" => RPL_LUSERCHANNELS,
+    "255" => RPL_LUSERME,
+    "265" => RPL_LOCALUSERS,
+    "266" => RPL_GLOBALUSERS,
+    // ADMIN
+    "256" => RPL_ADMINME,
+    "257" => RPL_ADMINLOC1,
+    "258" => RPL_ADMINLOC2,
+    "259" => RPL_ADMINEMAIL,
+    // AWAY
+    "301" => RPL_AWAY,
+    "305" => RPL_UNAWAY,
+    "306" => RPL_NOWAWAY,
+    // WHOIS
+    "311" => RPL_WHOISUSER,
+    "312" => RPL_WHOISSERVER,
+    "318" => RPL_ENDOFWHOIS,
+    "319" => RPL_WHOISCHANNELS,
+    "378" => RPL_WHOISHOST,
+    // WHO
+    "315" => RPL_ENDOFWHO,
+    "352" => RPL_WHOREPLY,
+    // LIST
+    "321" => RPL_LISTSTART,
+    "322" => RPL_LIST,
+    "323" => RPL_LISTEND,
+    // VERSION
+    "351" => RPL_VERSION,
+    // INFO
+    "371" => RPL_INFO,
+    "374" => RPL_ENDOFINFO,
+    // OPER
+    "381" => RPL_YOUREOPER,
+    "382" => RPL_REHASHING,
+    // STATS
+    "216" => RPL_STATSKLINE,
+    "219" => RPL_ENDOFSTATS,
+    "225" => RPL_STATSDLINE,
+    "242" => RPL_STATSUPTIME,
+    "247" => RPL_STATSGLINE,
+    // TIME
+    "391" => RPL_TIME,
     // Channels
+    "324" => RPL_CHANNELMODEIS,
+    "329" => RPL_CREATIONTIME,
+    "331" => RPL_NOTOPIC,
     "332" => RPL_TOPIC,
+    "333" => RPL_TOPICWHOTIME,
     "353" => RPL_NAMREPLY,
+    "366" => RPL_ENDOFNAMES,
     // MOTD
     "375" => RPL_MOTDSTART,
     "372" => RPL_MOTD,
     "376" => RPL_ENDOFMOTD,
+    // SASL
+    "900" => RPL_LOGGEDIN,
+    "901" => RPL_LOGGEDOUT,
+    "903" => RPL_SASLSUCCESS,
+    "908" => RPL_SASLMECHS,
+    // MONITOR
+    "730" => RPL_MONONLINE,
+    "731" => RPL_MONOFFLINE,
+    "732" => RPL_MONLIST,
+    "733" => RPL_ENDOFMONLIST,
+    // ISON, USERHOST
+    "302" => RPL_USERHOST,
+    "303" => RPL_ISON,
 }
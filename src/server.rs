@@ -1,100 +1,287 @@
-use crate::config::ServerConfig;
+use crate::config::{ServerConfig, CONFIG_PATH};
 use anyhow::{anyhow, Result};
 use chrono::prelude::*;
 use guard::guard;
 use irc_rust::Message;
 use std::{
-    io::{BufRead, BufReader, BufWriter},
+    io::{BufRead, BufReader, Write},
     net::{TcpListener, TcpStream},
     sync::{
+        atomic::AtomicU64,
         mpsc::{channel, Receiver, Sender},
-        Arc, RwLock,
+        Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
     },
-    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 mod action;
 mod action_parser;
+mod admission;
+mod ban_list;
+mod isupport;
+mod listener;
+mod lookup;
+mod message_history;
+mod motd;
 mod server_query;
 
 use action_parser::ActionParser;
+use itertools::Itertools;
 
-use self::server_query::ServerQuery;
-use crate::models::{Channel, User};
+use self::{
+    action::{Action, ClientWriter},
+    admission::Admission,
+    ban_list::BanList,
+    isupport::ISupport,
+    listener::Listener,
+    lookup::{LookupResult, LookupSettings},
+    message_history::MessageHistory,
+    motd::Motd,
+    server_query::ServerQuery,
+};
+use crate::models::{
+    Capability, Channel, Keepalive, MessageTags, User, UserMode, MAX_CLIENT_TAGS_LEN, MAX_TAGS_LEN,
+};
+use crate::numerics::ERR_INPUTTOOLONG;
+use crate::{cloak, mask};
 
 pub struct Server {
     config: ServerConfig,
+    motd: Option<Motd>,
     users: Vec<User>,
     channels: Vec<Channel>,
+    bans: Arc<RwLock<BanList>>,
+    history: MessageHistory,
     startup_time: DateTime<Utc>,
+    max_user_count: usize,
+    /// Counter making message IDs unique, see `ServerQuery::event_tags`.
+    msgid_counter: u64,
+    /// Counter of BATCH reference tags, see `ServerQuery::batch_reference`.
+    batch_counter: u64,
+    listener: Option<Listener>,
+    events: Option<Sender<ServerEvent>>,
+    client_ids: Arc<AtomicU64>,
+    admission: Arc<Mutex<Admission>>,
+    lookups: Arc<RwLock<LookupSettings>>,
 }
 
+/// How long a write may block before the client's SendQ counts as exceeded.
+const SENDQ_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Interval of timer events, e.g. for processing delayed commands.
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
 #[derive(Debug)]
 enum ServerEvent {
-    ClientConnected { stream: TcpStream, client_id: u64 },
-    ClientDisconnected { client_id: u64 },
-    IrcCommand { client_id: u64, message: String },
+    ClientConnected {
+        stream: TcpStream,
+        client_id: u64,
+        class: String,
+        lookup: LookupResult,
+    },
+    ClientDisconnected {
+        client_id: u64,
+    },
+    IrcCommand {
+        client_id: u64,
+        message: String,
+    },
+    Rehash,
+    Tick,
 }
 
 impl Server {
     pub fn new(config: ServerConfig) -> Self {
         Self {
+            motd: Self::load_motd(&config),
+            bans: Arc::new(RwLock::new(Self::load_bans(&config))),
+            history: Self::load_history(&config),
+            admission: Arc::new(Mutex::new(Admission::new(config.classes.clone()))),
+            lookups: Arc::new(RwLock::new(LookupSettings::new(&config))),
             config,
             users: vec![],
             channels: vec![],
             startup_time: Utc::now(),
+            max_user_count: 0,
+            msgid_counter: 0,
+            batch_counter: 0,
+            listener: None,
+            events: None,
+            client_ids: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    pub fn listen(&mut self) -> Result<()> {
-        let mut threads: Vec<JoinHandle<()>> = Vec::new();
+    /// Re-read the config file and apply it, moving the listener if the
+    /// address changed. A config that fails to load or validate is never
+    /// applied. Returns the top-level config keys that changed.
+    pub fn rehash(&mut self) -> Result<Vec<String>> {
+        let config = ServerConfig::read(CONFIG_PATH)?;
+        if config.addr() != self.config.addr() {
+            let listener = TcpListener::bind(config.addr())?;
+            self.start_listener(listener)?;
+        }
+
+        let changes = config.changed_keys(&self.config);
+        println!(
+            "[Server] Rehashed {} ({})",
+            CONFIG_PATH,
+            Action::describe_config_changes(&changes)
+        );
+        self.apply_config(config);
+        Ok(changes)
+    }
+
+    /// Accept clients on the given listener, replacing the current one.
+    fn start_listener(&mut self, listener: TcpListener) -> Result<()> {
+        guard!(let Some(sender) = self.events.clone() else {
+            return Err(anyhow!("Server is not listening."));
+        });
+        let listener = Listener::spawn(
+            listener,
+            self.client_ids.clone(),
+            self.bans.clone(),
+            self.admission.clone(),
+            self.lookups.clone(),
+            sender,
+        )?;
+        if let Some(previous) = self.listener.replace(listener) {
+            previous.stop();
+        }
+        Ok(())
+    }
+
+    /// Swap in a new configuration and inform registered clients
+    /// about ISUPPORT tokens that changed as a result.
+    pub fn apply_config(&mut self, config: ServerConfig) {
+        let tokens = ISupport::new(&config).changes_since(&ISupport::new(&self.config));
+        self.motd = Self::load_motd(&config);
+        if let Ok(mut admission) = self.admission.lock() {
+            admission.set_classes(config.classes.clone());
+        }
+        if let Ok(mut lookups) = self.lookups.write() {
+            *lookups = LookupSettings::new(&config);
+        }
+        if config.ban_file != self.bans().path() {
+            *self.bans_mut() = Self::load_bans(&config);
+        }
+        if config.history.file.as_deref() != self.history.path() {
+            self.history = Self::load_history(&config);
+        }
+        self.config = config;
+        if tokens.is_empty() {
+            return;
+        }
+
+        let clients = self
+            .users
+            .iter()
+            .filter(|user| user.registered)
+            .map(|user| user.client_id)
+            .collect_vec();
+        for client_id in clients {
+            let mut query = ServerQuery::new(self, client_id);
+            Action::ISupport {
+                tokens: tokens.clone(),
+            }
+            .dispatch_for_client_id(&mut query, client_id);
+        }
+    }
 
+    fn load_motd(config: &ServerConfig) -> Option<Motd> {
+        match Motd::load(config) {
+            Ok(motd) => Some(motd),
+            Err(err) => {
+                println!("[Server] Unable to load MOTD: {}", err);
+                None
+            }
+        }
+    }
+
+    /// The ban list is shared with the listener, which rejects D-lined
+    /// connections before looking them up.
+    fn bans(&self) -> RwLockReadGuard<'_, BanList> {
+        self.bans.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn bans_mut(&self) -> RwLockWriteGuard<'_, BanList> {
+        self.bans.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn load_bans(config: &ServerConfig) -> BanList {
+        match BanList::load(&config.ban_file) {
+            Ok(bans) => bans,
+            Err(err) => {
+                println!("[Server] Unable to load bans: {}", err);
+                BanList::empty(&config.ban_file)
+            }
+        }
+    }
+
+    fn load_history(config: &ServerConfig) -> MessageHistory {
+        match MessageHistory::load(&config.history) {
+            Ok(history) => history,
+            Err(err) => {
+                println!("[Server] Unable to load message history: {}", err);
+                MessageHistory::empty(&config.history)
+            }
+        }
+    }
+
+    pub fn listen(&mut self) -> Result<()> {
         let (sender, recv) = channel::<ServerEvent>();
         let listener = TcpListener::bind(self.config.addr())?;
 
-        thread::spawn(move || {
-            let client_count = Arc::new(RwLock::new(0u64));
-            let assign_client_id = || -> Result<u64> {
-                let client_count = client_count.clone();
-                let mut writer = client_count
-                    .write()
-                    .map_err(|_| anyhow!("Unable to acquire mutable client count."))?;
-                *writer += 1;
-                Ok(*writer)
-            };
-
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        let sender = sender.clone();
-                        let client_id = match assign_client_id() {
-                            Ok(client_id) => client_id,
-                            _ => return,
-                        };
-                        let handle = thread::spawn(move || {
-                            if let Err(err) = Self::handle_stream(stream, client_id, sender) {
-                                println!("[Client(id: {})] Error: {}", client_id, err);
-                            }
-                        });
-                        threads.push(handle);
-                    }
-                    Err(err) => {
-                        println!("Unable to accept client stream: {}", err)
-                    }
-                }
+        #[cfg(unix)]
+        Self::handle_signals(sender.clone())?;
+
+        // Emit timer events
+        let tick_sender = sender.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(TICK_INTERVAL);
+            if tick_sender.send(ServerEvent::Tick).is_err() {
+                break;
             }
         });
 
+        self.events = Some(sender);
+        self.start_listener(listener)?;
         self.handle_commands(recv);
 
         Ok(())
     }
 
-    fn handle_stream(stream: TcpStream, client_id: u64, sender: Sender<ServerEvent>) -> Result<()> {
+    /// Reload the config on SIGHUP.
+    #[cfg(unix)]
+    fn handle_signals(sender: Sender<ServerEvent>) -> Result<()> {
+        use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+        let mut signals = Signals::new([SIGHUP])?;
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                if sender.send(ServerEvent::Rehash).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn handle_stream(
+        stream: TcpStream,
+        client_id: u64,
+        class: &str,
+        lookups: &LookupSettings,
+        sender: Sender<ServerEvent>,
+    ) -> Result<()> {
+        // Look up hostname and ident before registering the client
+        let lookup = lookup::perform(&stream, lookups)?;
+
         // Register client with server
         sender.send(ServerEvent::ClientConnected {
             stream: stream.try_clone()?,
             client_id,
+            class: class.to_string(),
+            lookup,
         })?;
 
         // Get a buffered reader for the incoming data
@@ -125,7 +312,24 @@ impl Server {
 
     fn handle_commands(&mut self, receiver: Receiver<ServerEvent>) {
         for command in receiver {
-            self.handle_command(command)
+            self.handle_command(command);
+            self.disconnect_sendq_exceeded();
+        }
+    }
+
+    fn disconnect_sendq_exceeded(&mut self) {
+        let clients = self
+            .users
+            .iter()
+            .filter(|user| user.sendq_exceeded)
+            .map(|user| user.client_id)
+            .collect_vec();
+        for client_id in clients {
+            let mut query = ServerQuery::new(self, client_id);
+            Action::Disconnect {
+                reason: String::from("Max SendQ exceeded"),
+            }
+            .dispatch_for_client_id(&mut query, client_id);
         }
     }
 
@@ -139,45 +343,232 @@ impl Server {
         };
 
         match command {
-            ServerEvent::ClientConnected { stream, client_id } => {
+            ServerEvent::ClientConnected {
+                stream,
+                client_id,
+                class,
+                lookup,
+            } => {
                 guard!(let Ok(peer_addr) = stream.peer_addr() else { return });
-                let user = User::new(stream, client_id, peer_addr.to_string());
+
+                // Reject connections D-lined while they were being looked up
+                let dline = self.bans().find_dline(peer_addr.ip()).cloned();
+                if let Some(ban) = dline {
+                    let error = format!(
+                        "ERROR :Closing Link: {} (D-lined ({}))\r\n",
+                        peer_addr.ip(),
+                        ban.reason
+                    );
+                    let _ = (&stream).write_all(error.as_bytes());
+                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                    println!(
+                        "[Server] Rejected D-lined connection from {}",
+                        peer_addr.ip()
+                    );
+                    return;
+                }
+
+                // Bound the data waiting to be sent to the client
+                let sendq = self.config.connect_class(&class).sendq;
+                let _ = socket2::SockRef::from(&stream).set_send_buffer_size(sendq as usize);
+                let _ = stream.set_write_timeout(Some(SENDQ_WRITE_TIMEOUT));
+
+                let mut user =
+                    User::new(stream, client_id, peer_addr.ip(), class, &self.config.flood);
+                if let Some(hostname) = lookup.hostname {
+                    user.host = hostname;
+                }
+                user.ident = lookup.ident;
+                if self.config.cloak.enabled {
+                    user.cloaked_host =
+                        Some(cloak::cloak_host(&self.config.cloak, &user.host, user.ip));
+                    user.modes.push(UserMode::Cloaked);
+                }
                 self.users.push(user);
                 dbg!(&self.users);
             }
 
             ServerEvent::ClientDisconnected { client_id } => {
-                if let Some(index) = find_user_index_by_client_id(client_id) {
-                    self.users.swap_remove(index);
+                if find_user_index_by_client_id(client_id).is_some() {
+                    let mut query = ServerQuery::new(self, client_id);
+                    Action::Disconnect {
+                        reason: String::from("Remote host closed the connection"),
+                    }
+                    .dispatch_for_client_id(&mut query, client_id);
                 }
                 dbg!(&self.users);
             }
 
+            ServerEvent::Rehash => {
+                let text = match self.rehash() {
+                    Ok(changes) => format!(
+                        "Got SIGHUP, reloaded {} ({})",
+                        CONFIG_PATH,
+                        Action::describe_config_changes(&changes)
+                    ),
+                    Err(err) => format!("Got SIGHUP, unable to reload {}: {}", CONFIG_PATH, err),
+                };
+                let mut query = ServerQuery::new(self, 0);
+                Action::broadcast_server_notice(&mut query, text);
+            }
+
+            ServerEvent::Tick => {
+                let clients = self
+                    .users
+                    .iter()
+                    .filter(|user| user.throttle.queued() > 0)
+                    .map(|user| user.client_id)
+                    .collect_vec();
+                for client_id in clients {
+                    self.process_throttled_commands(client_id);
+                }
+                self.check_keepalive();
+            }
+
             ServerEvent::IrcCommand { client_id, message } => {
                 println!("[{} ->] {}", client_id, message.trim_end());
 
-                // Get a mutable writer for the user's stream
-                let mut writer = {
-                    guard!(let Some(user) = self.users.iter().find(|user| user.client_id == client_id) else { return });
-                    let writer = user
-                        .stream
-                        .try_clone()
-                        .ok()
-                        .map(|stream| BufWriter::new(stream));
-                    guard!(let Some(mut writer) = writer else { return });
-                    writer
-                };
+                guard!(let Some(index) = find_user_index_by_client_id(client_id) else { return });
+                let user = &mut self.users[index];
+                user.last_activity = Instant::now();
+                user.ping_sent_at = None;
+                if self.is_flood_exempt(&self.users[index]) {
+                    self.process_command(client_id, message);
+                    return;
+                }
+
+                // Delay the command if the client is sending too fast
+                let throttle = &mut self.users[index].throttle;
+                throttle.push(message);
+                if throttle.queued() > self.config.flood.max_queue as usize {
+                    let mut query = ServerQuery::new(self, client_id);
+                    Action::Disconnect {
+                        reason: String::from("Excess Flood"),
+                    }
+                    .dispatch_for_client_id(&mut query, client_id);
+                    return;
+                }
+                self.process_throttled_commands(client_id);
+            }
+        }
+    }
+
+    /// Ping idle clients, and disconnect clients that stopped responding
+    /// or didn't complete registration in time.
+    fn check_keepalive(&mut self) {
+        let mut pings = vec![];
+        let mut timeouts = vec![];
+        for user in self.users.iter() {
+            let class = self.config.connect_class(&user.class);
+            let keepalive = user.keepalive(
+                Duration::from_secs(class.ping_frequency),
+                Duration::from_secs(class.registration_timeout),
+            );
+            match keepalive {
+                Keepalive::Ping => pings.push(user.client_id),
+                Keepalive::Timeout(reason) => timeouts.push((user.client_id, reason)),
+                Keepalive::Alive => {}
+            }
+        }
+
+        let token = self.config.host.clone();
+        for client_id in pings {
+            if let Some(user) = self
+                .users
+                .iter_mut()
+                .find(|user| user.client_id == client_id)
+            {
+                user.ping_sent_at = Some(Instant::now());
+            }
+            let mut query = ServerQuery::new(self, client_id);
+            Action::Ping {
+                token: token.clone(),
+            }
+            .dispatch_for_client_id(&mut query, client_id);
+        }
+        for (client_id, reason) in timeouts {
+            let mut query = ServerQuery::new(self, client_id);
+            Action::Disconnect { reason }.dispatch_for_client_id(&mut query, client_id);
+        }
+    }
 
-                // Initialize server query with mutable self and client id
-                let mut query = ServerQuery::new(self, client_id);
+    /// Operators and clients matching `flood.exempt` aren't throttled.
+    fn is_flood_exempt(&self, user: &User) -> bool {
+        guard!(let Some(username) = &user.username else { return false });
+        let user_host = format!("{}@{}", username, user.host);
+        user.has_mode(UserMode::Operator)
+            || self
+                .config
+                .flood
+                .exempt
+                .iter()
+                .any(|mask| mask::matches(mask, &user_host))
+    }
 
-                // Parse IRC message
-                let message = Message::from(message.trim_end());
-                guard!(let Some(action) = ActionParser::parse(message, &mut query) else { return });
+    /// Process delayed commands of a client as far as its throttle allows.
+    fn process_throttled_commands(&mut self, client_id: u64) {
+        loop {
+            let flood = &self.config.flood;
+            let user = self
+                .users
+                .iter_mut()
+                .find(|user| user.client_id == client_id);
+            let message = user.and_then(|user| {
+                let batch = user
+                    .multiline
+                    .as_ref()
+                    .map(|batch| batch.reference.as_str());
+                user.throttle.pop_ready(flood, batch)
+            });
+            guard!(let Some(message) = message else { break });
+            self.process_command(client_id, message);
+        }
+    }
+
+    fn process_command(&mut self, client_id: u64, message: String) {
+        // Split off message tags, only client-only tags are relayed
+        let (raw_tags, line) = MessageTags::split(message.trim_end());
+        let tags = raw_tags.map(MessageTags::parse).unwrap_or_default();
+        let client_tags = tags.client_only();
+        let tags_too_long = raw_tags.map_or(0, |raw_tags| raw_tags.len() + 2) > MAX_TAGS_LEN
+            || client_tags.len_raw() > MAX_CLIENT_TAGS_LEN;
 
-                // Dispatch the action
-                action.dispatch(&mut query, &mut writer);
+        // Get a mutable writer for the user's stream
+        guard!(let Some(user) = self.users.iter().find(|user| user.client_id == client_id) else { return });
+        let label = tags
+            .get("label")
+            .filter(|_| user.has_capability(Capability::LabeledResponse))
+            .map(ToString::to_string);
+        let mut writer = {
+            let writer = user.stream.try_clone().ok().map(ClientWriter::new);
+            guard!(let Some(mut writer) = writer else { return });
+            writer
+        };
+
+        // Initialize server query with mutable self and client id.
+        // Responses to labeled commands are collected, see below.
+        let mut query = ServerQuery::new(self, client_id);
+        if let Some(label) = label {
+            query.user_mut().labeled_responses = Some((label, vec![]));
+            writer.capture();
+        }
+
+        if tags_too_long {
+            Action::Error {
+                code: ERR_INPUTTOOLONG,
+            }
+            .dispatch(&mut query, &mut writer);
+        } else if let Some(action) = ActionParser::parse(Message::from(line), tags, &mut query) {
+            // Check operator privileges, then dispatch the action
+            match action.required_privilege() {
+                Some(privilege) if !query.user_has_privilege(privilege) => {
+                    Action::PermissionDenied { privilege }.dispatch(&mut query, &mut writer);
+                }
+                _ => action.dispatch(&mut query, &mut writer),
             }
         }
+
+        Action::send_labeled_response(&mut query, client_id, &mut writer);
+        Action::flush(&mut query, client_id, &mut writer);
     }
 }
@@ -1,23 +1,97 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::prelude::*;
 use guard::guard;
 use irc_rust::{Message, MessageBuilder};
 use itertools::Itertools;
 use std::{
-    io::{BufWriter, Write},
+    io::{self, BufWriter, ErrorKind, Write},
     net::TcpStream,
 };
 
-use super::server_query::ServerQuery;
+use super::{isupport::ISupport, server_query::ServerQuery};
 use crate::{
-    models::{ChannelRef, User},
+    config::{Privilege, CONFIG_PATH},
+    mask,
+    models::{
+        format_time, Ban, BanKind, Capability, ChannelMode, ChannelRef, HistoryEntry,
+        HistorySelector, ListFilter, MessageKind, MessageTags, MultilineBatch, MultilineLine,
+        ReplyKind, User, UserMode, MULTILINE_MAX_BYTES, MULTILINE_MAX_LINES,
+    },
     numerics::*,
 };
 
+/// Writes messages to a client's stream. While a labeled command is processed,
+/// messages are captured instead, to be sent as one labeled response.
+pub struct ClientWriter {
+    writer: BufWriter<TcpStream>,
+    captured: Option<Vec<Message>>,
+}
+
+impl ClientWriter {
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            writer: BufWriter::new(stream),
+            captured: None,
+        }
+    }
+
+    /// Capture messages until `take_captured` is called.
+    pub fn capture(&mut self) {
+        self.captured.get_or_insert_with(Vec::new);
+    }
+
+    /// Stop capturing, returning the captured messages.
+    pub fn take_captured(&mut self) -> Vec<Message> {
+        self.captured.take().unwrap_or_default()
+    }
+
+    fn send(&mut self, message: Message) {
+        if let Some(captured) = &mut self.captured {
+            captured.push(message);
+            return;
+        }
+        let line = format!("{}\r\n", message);
+        if let Err(err) = self.writer.write_all(line.as_bytes()) {
+            println!("[Dispatch] Error: {}", err);
+        }
+    }
+
+    /// Write buffered messages, captured messages aren't affected.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
 const SOFTWARE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+/// Maximum length of the target list of a MONITOR reply.
+const MONITOR_LINE_LEN: usize = 400;
+
+/// Maximum length of the nickname list in a single RPL_NAMREPLY.
+const NAMES_LINE_LEN: usize = 400;
+
+/// Size of the chunks AUTHENTICATE data is split into.
+const SASL_CHUNK_LEN: usize = 400;
+
+/// Maximum size of the base64 data of a SASL exchange.
+const SASL_MAX_LEN: usize = 4096;
+
 pub enum Action {
     Error {
         code: &'static str,
     },
+    /// IRCv3 standard reply, sent as a server notice to clients
+    /// that didn't negotiate `standard-replies`.
+    StandardReply {
+        kind: ReplyKind,
+        command: String,
+        code: String,
+        context: Vec<String>,
+        description: String,
+    },
+    Ping {
+        token: String,
+    },
     Pong {
         challenge: Option<String>,
     },
@@ -33,42 +107,283 @@ pub enum Action {
         realname: String,
     },
     SendWelcomeSequence,
+    ISupport {
+        tokens: Vec<String>,
+    },
+    CapLs {
+        version: u32,
+    },
+    CapList,
+    CapReq {
+        capabilities: Vec<String>,
+    },
+    CapEnd,
+    CapInvalid {
+        subcommand: String,
+    },
     Motd,
+    Oper {
+        name: String,
+        password: String,
+    },
+    PermissionDenied {
+        privilege: Privilege,
+    },
+    ServerNotice {
+        text: String,
+    },
+    Die,
+    Lusers,
+    Version,
+    Time,
+    Admin,
+    Info,
     Quit {
         reason: Option<String>,
     },
+    Rehash,
+    AddBan {
+        kind: BanKind,
+        mask: String,
+        duration: Option<i64>,
+        reason: String,
+    },
+    RemoveBan {
+        kind: BanKind,
+        mask: String,
+    },
+    Stats {
+        letter: char,
+    },
+    Disconnect {
+        reason: String,
+    },
+    Kill {
+        nickname: String,
+        reason: String,
+    },
+    Wallops {
+        message: String,
+    },
+    Globops {
+        message: String,
+    },
+    Globop {
+        from_nickname: String,
+        message: String,
+    },
     Join {
         channels: Vec<ChannelRef>,
     },
     JoinInform {
         channel: String,
+        nickname: String,
+        username: String,
+        host: String,
+        account: Option<String>,
+        realname: String,
+        tags: MessageTags,
     },
+    /// `lines` are those of a `draft/multiline` message, `message` then holds all of them.
     PrivateMessage {
+        kind: MessageKind,
         message: String,
+        lines: Vec<MultilineLine>,
         users: Vec<String>,
         channels: Vec<String>,
+        tags: MessageTags,
     },
     PrivateMessageUser {
+        kind: MessageKind,
         message: String,
+        lines: Vec<MultilineLine>,
         from_nickname: String,
+        tags: MessageTags,
     },
     PrivateMessageChannel {
+        kind: MessageKind,
         message: String,
+        lines: Vec<MultilineLine>,
         channel: String,
         from_nickname: String,
+        tags: MessageTags,
+    },
+    /// Start of a `draft/multiline` batch, `tags` are its client-only tags.
+    MultilineStart {
+        reference: String,
+        target: String,
+        tags: MessageTags,
+    },
+    /// PRIVMSG or NOTICE tagged with the reference of a `draft/multiline` batch.
+    MultilineLine {
+        reference: String,
+        kind: MessageKind,
+        target: String,
+        text: String,
+        concat: bool,
+    },
+    MultilineEnd {
+        reference: String,
+    },
+    Away {
+        message: Option<String>,
+    },
+    ChatHistory {
+        target: String,
+        selector: HistorySelector,
+        limit: usize,
+    },
+    ChatHistoryTargets {
+        between: (i64, i64),
+        limit: usize,
+    },
+    AwayNotify {
+        nickname: String,
+        username: String,
+        host: String,
+        message: Option<String>,
+        tags: MessageTags,
+    },
+    MonitorAdd {
+        targets: Vec<String>,
+    },
+    MonitorRemove {
+        targets: Vec<String>,
+    },
+    MonitorClear,
+    MonitorList,
+    MonitorStatus,
+    /// Inform a client monitoring `nickname` that it came online
+    /// (with its `nick!user@host` mask) or went offline.
+    MonitorInform {
+        nickname: String,
+        mask: Option<String>,
+    },
+    Ison {
+        nicknames: Vec<String>,
+    },
+    Userhost {
+        nicknames: Vec<String>,
+    },
+    SetName {
+        realname: String,
+    },
+    /// Change the displayed host of another user.
+    ChgHost {
+        nickname: String,
+        host: String,
+    },
+    /// Announce that the user's displayed host changed from `old_host`.
+    HostChange {
+        old_host: String,
+    },
+    /// Inform a channel peer that the host of a user changed, using CHGHOST
+    /// or a simulated QUIT and JOIN for clients that didn't negotiate `chghost`.
+    HostChangeInform {
+        client_id: u64,
+        old_host: String,
+        tags: MessageTags,
+    },
+    Authenticate {
+        data: String,
+    },
+    AccountNotify {
+        nickname: String,
+        username: String,
+        host: String,
+        account: String,
+        tags: MessageTags,
+    },
+    Who {
+        mask: String,
+    },
+    Whois {
+        nickname: String,
+    },
+    List {
+        filters: Vec<ListFilter>,
+    },
+    ChannelMode {
+        channel: String,
+        changes: Vec<(bool, char)>,
+        params: Vec<String>,
+    },
+    UserMode {
+        nickname: String,
+        changes: Vec<(bool, char)>,
+    },
+    Topic {
+        channel: String,
+        topic: Option<String>,
+    },
+    Names {
+        channels: Vec<String>,
+    },
+    Kick {
+        channel: String,
+        users: Vec<String>,
+        comment: Option<String>,
+    },
+    Relay {
+        message: Message,
+    },
+    /// Relay an event of another client, with the tags the recipient supports.
+    RelayEvent {
+        message: Message,
+        tags: MessageTags,
+    },
+    /// Send messages as a batch to clients that negotiated `batch`,
+    /// `tags` are added to the line starting the batch.
+    Batch {
+        batch_type: String,
+        params: Vec<String>,
+        tags: MessageTags,
+        messages: Vec<Message>,
+    },
+    /// Send the responses to a command tagged with `label`.
+    LabeledResponse {
+        label: String,
+        responses: Vec<Message>,
     },
 }
 
 impl Action {
+    /// Operator privilege required to perform the action.
+    pub fn required_privilege(&self) -> Option<Privilege> {
+        match self {
+            Action::Die => Some(Privilege::Die),
+            Action::Rehash => Some(Privilege::Rehash),
+            Action::AddBan { .. } | Action::RemoveBan { .. } => Some(Privilege::Ban),
+            Action::Stats { letter } if "kdgKDG".contains(*letter) => Some(Privilege::Ban),
+            Action::Kill { .. } => Some(Privilege::Kill),
+            Action::ChgHost { .. } => Some(Privilege::Chghost),
+            Action::Wallops { .. } => Some(Privilege::Wallops),
+            Action::Globops { .. } => Some(Privilege::Globops),
+            _ => None,
+        }
+    }
+
+    /// Summarize the config keys changed by a rehash.
+    pub fn describe_config_changes(changes: &[String]) -> String {
+        if changes.is_empty() {
+            String::from("no changes")
+        } else {
+            format!("changed: {}", changes.join(", "))
+        }
+    }
+
+    /// Send a server notice to all operators that receive them.
+    pub fn broadcast_server_notice(query: &mut ServerQuery, text: String) {
+        println!("[Server] Notice: {}", text);
+        let recipients = query.server_notice_recipients();
+        Action::ServerNotice { text }.dispatch_multi_by_client_id(query, &recipients[..]);
+    }
+
     pub fn _dispatch_multi_by_user_ref(&self, root_query: &mut ServerQuery, users: &[&User]) {
         for user in users {
             let mut query = ServerQuery::new(root_query.server_mut(), user.client_id);
             let mut writer = {
-                let writer = user
-                    .stream
-                    .try_clone()
-                    .ok()
-                    .map(|stream| BufWriter::new(stream));
+                let writer = user.stream.try_clone().ok().map(ClientWriter::new);
                 guard!(let Some(mut writer) = writer else { return });
                 writer
             };
@@ -78,17 +393,52 @@ impl Action {
 
     pub fn dispatch_for_client_id(&self, root_query: &mut ServerQuery, client: u64) {
         let mut query = ServerQuery::new(root_query.server_mut(), client);
+        if query.user_find_by_client_id(client).is_none() {
+            return;
+        }
         let mut writer = {
-            let writer = query
-                .user()
-                .stream
-                .try_clone()
-                .ok()
-                .map(|stream| BufWriter::new(stream));
+            let writer = query.user().stream.try_clone().ok().map(ClientWriter::new);
             guard!(let Some(mut writer) = writer else { return });
             writer
         };
+        if query.user().labeled_responses.is_some() {
+            writer.capture();
+        }
         self.dispatch(&mut query, &mut writer);
+
+        // Output for a client processing a labeled command is part of its response
+        let captured = writer.take_captured();
+        let labeled_responses = query
+            .user_find_by_client_id_mut(client)
+            .and_then(|user| user.labeled_responses.as_mut());
+        if let Some((_, responses)) = labeled_responses {
+            responses.extend(captured);
+            return;
+        }
+        Self::flush(&mut query, client, &mut writer);
+    }
+
+    /// Send the responses collected while a labeled command was processed as
+    /// one labeled response. Output of actions dispatched for the client by
+    /// client ID comes first, as it would otherwise have been flushed first.
+    pub fn send_labeled_response(query: &mut ServerQuery, client: u64, writer: &mut ClientWriter) {
+        let labeled_responses = query
+            .user_find_by_client_id_mut(client)
+            .and_then(|user| user.labeled_responses.take());
+        guard!(let Some((label, mut responses)) = labeled_responses else { return });
+        responses.extend(writer.take_captured());
+        Action::LabeledResponse { label, responses }.dispatch(query, writer);
+    }
+
+    /// Flush a client's writer. Writes time out once the client's SendQ is
+    /// full, in which case the client is marked for disconnection.
+    pub fn flush(query: &mut ServerQuery, client: u64, writer: &mut ClientWriter) {
+        guard!(let Err(err) = writer.flush() else { return });
+        if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+            if let Some(user) = query.user_find_by_client_id_mut(client) {
+                user.sendq_exceeded = true;
+            }
+        }
     }
 
     pub fn dispatch_multi_by_client_id(&self, root_query: &mut ServerQuery, clients: &[u64]) {
@@ -97,21 +447,24 @@ impl Action {
         }
     }
 
-    pub fn dispatch(&self, query: &mut ServerQuery, writer: &mut BufWriter<TcpStream>) {
+    pub fn dispatch(&self, query: &mut ServerQuery, writer: &mut ClientWriter) {
         let mut send = |message: Message| {
             println!("[Dispatch] {}", message);
-            let msg = format!("{}\r\n", message.to_string());
-            if let Err(err) = writer.write_all(msg.as_ref()) {
-                println!("[Dispatch] Error: {}", err);
-            }
+            writer.send(message);
         };
 
-        let server_host = query.server_host();
+        let server_host = &query.server_host().to_string();
         let user_host = query.user_host();
+        let visible_host = query.user().visible_host().to_string();
         let client_id = query.user().client_id;
 
         match self {
             // Send PING response
+            Action::Ping { token } => {
+                let ping_command = MessageBuilder::new("PING").trailing(token).build();
+                send(ping_command);
+            }
+
             Action::Pong { challenge } => {
                 let mut message = MessageBuilder::new("PONG");
                 if let Some(challenge) = challenge {
@@ -127,6 +480,7 @@ impl Action {
                     user_host, nickname
                 );
                 query.user_mut().nickname = Some(nickname.clone());
+                Self::try_complete_registration(query, writer);
             }
 
             Action::ChangeNick {
@@ -138,6 +492,18 @@ impl Action {
                     user_host, prev_nickname, nickname
                 );
                 query.user_mut().nickname = Some(nickname.clone());
+
+                // Channel operator status is tracked by nickname
+                for channel in query.server_mut().channels.iter_mut() {
+                    channel.rename_op(prev_nickname, nickname);
+                }
+
+                if query.user().registered {
+                    let username = query.user().username.clone().unwrap();
+                    let mask = format!("{}!{}@{}", nickname, username, visible_host);
+                    Self::inform_monitors(query, prev_nickname, None);
+                    Self::inform_monitors(query, nickname, Some(mask));
+                }
             }
 
             Action::SetUserAndRealName { username, realname } => {
@@ -147,11 +513,23 @@ impl Action {
                 );
                 query.user_mut().username = Some(username.clone());
                 query.user_mut().realname = Some(realname.clone());
-                Action::SendWelcomeSequence.dispatch(query, writer);
+                Self::try_complete_registration(query, writer);
             }
 
             Action::SendWelcomeSequence => {
                 println!("[Server] #welcome[client={}]", user_host);
+                query.user_mut().registered = true;
+                query.update_max_user_count();
+                Self::broadcast_server_notice(
+                    query,
+                    format!(
+                        "Client connecting: {} ({}@{}) [{}]",
+                        query.user().nickname.clone().unwrap(),
+                        query.user().username.clone().unwrap(),
+                        user_host,
+                        query.user().realname.clone().unwrap_or_default()
+                    ),
+                );
                 let nickname = query.user().nickname.clone().unwrap();
                 let rpl_welcome = MessageBuilder::new(RPL_WELCOME)
                     .param(&nickname)
@@ -175,184 +553,2547 @@ impl Action {
                         server_startup_time = query.server_startup_time()
                     ))
                     .build();
-                // TODO: RPL_MYINFO
-                let _rpl_myinfo = MessageBuilder::new(RPL_MYINFO).param(&nickname).build();
-                let rpl_isupport = MessageBuilder::new(RPL_ISUPPORT)
-                    .param(&nickname)
-                    .param(&format!("AWAYLEN={}", query.server_config().feat_awaylen))
-                    .param(&format!(
-                        "CASEMAPPING={}",
-                        query.server_config().feat_casemap.to_string()
-                    ))
-                    .trailing("are supported by this server")
-                    .build();
-                let rpl_lusers = MessageBuilder::new(RPL_LUSERCLIENT)
+                let (channel_modes, channel_param_modes) = ISupport::channel_modes();
+                let rpl_myinfo = MessageBuilder::new(RPL_MYINFO)
                     .param(&nickname)
-                    .trailing(&format!(
-                        "There are {user_count} users and {invisible_count} invisible on 1 server",
-                        user_count = query.user_count(),
-                        invisible_count = 0
-                    ))
+                    .param(server_host)
+                    .param(&format!("myriad-{}", SOFTWARE_VERSION))
+                    .param(&ISupport::user_modes())
+                    .param(&channel_modes)
+                    .param(&channel_param_modes)
                     .build();
                 send(rpl_welcome);
                 send(rpl_yourhost);
                 send(rpl_created);
-                // send(rpl_myinfo);
-                send(rpl_isupport);
-                send(rpl_lusers);
+                send(rpl_myinfo);
+                let tokens = ISupport::new(query.server_config()).tokens();
+                for rpl_isupport in Self::isupport_messages(server_host, &nickname, &tokens) {
+                    send(rpl_isupport);
+                }
+                if query.user().has_mode(UserMode::Cloaked) {
+                    let mode_command = MessageBuilder::new("MODE")
+                        .prefix(&nickname, None, None)
+                        .param(&nickname)
+                        .trailing(&query.user().mode_string())
+                        .build();
+                    send(mode_command);
+                }
+                if visible_host != query.user().host {
+                    send(Self::host_hidden_message(query, server_host));
+                }
+                let username = query.user().username.clone().unwrap();
+                let mask = format!("{}!{}@{}", nickname, username, visible_host);
+                Self::inform_monitors(query, &nickname, Some(mask));
+                Action::Lusers.dispatch(query, writer);
                 Action::Motd.dispatch(query, writer);
             }
 
+            Action::CapLs { version } => {
+                // Registration is suspended until the client ends negotiation
+                if !query.user().registered {
+                    query.user_mut().cap_negotiating = true;
+                }
+                println!(
+                    "[Server] CAP LS [client={}, version={}]",
+                    user_host, version
+                );
+                // Capability values were introduced with CAP LS 302
+                let capabilities = Capability::ALL
+                    .iter()
+                    .map(|cap| match cap.value() {
+                        Some(value) if *version >= 302 => format!("{}={}", cap.name(), value),
+                        _ => cap.name().to_string(),
+                    })
+                    .join(" ");
+                let cap_ls = MessageBuilder::new("CAP")
+                    .prefix(server_host, None, None)
+                    .param(&query.user().nickname_or_star())
+                    .param("LS")
+                    .trailing(&capabilities)
+                    .build();
+                send(cap_ls);
+            }
+
+            Action::CapList => {
+                let capabilities = query
+                    .user()
+                    .capabilities
+                    .iter()
+                    .map(Capability::name)
+                    .join(" ");
+                let cap_list = MessageBuilder::new("CAP")
+                    .prefix(server_host, None, None)
+                    .param(&query.user().nickname_or_star())
+                    .param("LIST")
+                    .trailing(&capabilities)
+                    .build();
+                send(cap_list);
+            }
+
+            Action::CapReq { capabilities } => {
+                if !query.user().registered {
+                    query.user_mut().cap_negotiating = true;
+                }
+
+                // Resolve requested capabilities, a leading '-' disables the capability
+                let requested = capabilities
+                    .iter()
+                    .map(|name| match name.strip_prefix('-') {
+                        Some(name) => Capability::from_name(name).map(|cap| (cap, false)),
+                        None => Capability::from_name(name).map(|cap| (cap, true)),
+                    })
+                    .collect::<Option<Vec<_>>>();
+
+                // The request is applied atomically: either all or none of the changes
+                let reply = if let Some(requested) = requested.filter(|req| !req.is_empty()) {
+                    let user = query.user_mut();
+                    for (capability, enable) in requested {
+                        user.capabilities.retain(|cap| *cap != capability);
+                        if enable {
+                            user.capabilities.push(capability);
+                        }
+                    }
+                    "ACK"
+                } else {
+                    "NAK"
+                };
+                println!(
+                    "[Server] CAP REQ [client={}, capabilities='{}', reply={}]",
+                    user_host,
+                    capabilities.join(" "),
+                    reply
+                );
+                let cap_reply = MessageBuilder::new("CAP")
+                    .prefix(server_host, None, None)
+                    .param(&query.user().nickname_or_star())
+                    .param(reply)
+                    .trailing(&capabilities.join(" "))
+                    .build();
+                send(cap_reply);
+            }
+
+            Action::CapEnd => {
+                query.user_mut().cap_negotiating = false;
+                Self::try_complete_registration(query, writer);
+            }
+
+            Action::CapInvalid { subcommand } => {
+                let err_invalidcapcmd = MessageBuilder::new(ERR_INVALIDCAPCMD)
+                    .prefix(server_host, None, None)
+                    .param(&query.user().nickname_or_star())
+                    .param(subcommand)
+                    .trailing("Invalid CAP command")
+                    .build();
+                send(err_invalidcapcmd);
+            }
+
+            Action::ISupport { tokens } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                for rpl_isupport in Self::isupport_messages(server_host, &nickname, tokens) {
+                    send(rpl_isupport);
+                }
+            }
+
             Action::Motd => {
                 let nickname = query.user().nickname.clone().unwrap();
                 let motd_start = MessageBuilder::new(RPL_MOTDSTART)
                     .param(&nickname)
                     .trailing(&format!("- {} Message of the day - ", query.server_name()))
                     .build();
-                let motd = MessageBuilder::new(RPL_MOTD)
-                    .param(&nickname)
-                    .trailing(&query.server_config().motd)
-                    .build();
+                let variables = [
+                    ("server_name", query.server_name().to_string()),
+                    ("network", query.server_config().network.clone()),
+                    ("host", server_host.to_string()),
+                    ("version", SOFTWARE_VERSION.to_string()),
+                    ("nickname", nickname.clone()),
+                    ("user_count", query.registered_user_count().to_string()),
+                    ("max_user_count", query.max_user_count().to_string()),
+                    ("channel_count", query.channel_count().to_string()),
+                ];
+                guard!(let Some(lines) = query.server_motd().map(|motd| motd.render(&variables)) else {
+                    let err_nomotd = MessageBuilder::new(ERR_NOMOTD)
+                        .param(&nickname)
+                        .trailing("MOTD File is missing")
+                        .build();
+                    send(err_nomotd);
+                    return;
+                });
                 let motd_end = MessageBuilder::new(RPL_ENDOFMOTD)
                     .param(&nickname)
                     .trailing("End of /MOTD command.")
                     .build();
                 send(motd_start);
-                send(motd);
+                for line in lines {
+                    let motd = MessageBuilder::new(RPL_MOTD)
+                        .param(&nickname)
+                        .trailing(&line)
+                        .build();
+                    send(motd);
+                }
                 send(motd_end);
             }
 
-            Action::Join { channels } => {
+            Action::Oper { name, password } => {
                 let nickname = query.user().nickname.clone().unwrap();
+                let user_mask = format!("{}@{}", query.user().username.clone().unwrap(), user_host);
 
-                for channel_ref in channels {
-                    // Create channel if it doesn't exist
-                    let channel = query.channel_get_or_create(&channel_ref.name);
+                // Validate the operator block
+                let oper = query.server_config().find_oper(name);
+                let host_allowed = oper.is_some_and(|oper| {
+                    oper.hosts
+                        .iter()
+                        .any(|mask| mask::matches(mask, &user_mask))
+                });
+                // Plaintext connections can't satisfy TLS or certificate requirements
+                let tls_satisfied =
+                    oper.is_some_and(|oper| !oper.require_tls && oper.certfp.is_none());
+                let oper_class = oper.map(|oper| oper.class.clone());
 
-                    // Join client into channel
-                    channel.join_user(client_id);
+                // Check the host first, so rejected hosts can't probe passwords
+                if !host_allowed || !tls_satisfied {
+                    Self::broadcast_server_notice(
+                        query,
+                        format!(
+                            "Failed OPER attempt by {} ({}) using name {}: host not allowed",
+                            nickname, user_mask, name
+                        ),
+                    );
+                    let err_nooperhost = MessageBuilder::new(ERR_NOOPERHOST)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .trailing("No O-lines for your host")
+                        .build();
+                    send(err_nooperhost);
+                    return;
+                }
 
-                    // Send topic
-                    if !channel.topic().is_empty() {
-                        let rpl_topic = MessageBuilder::new(RPL_TOPIC)
-                            .param(&nickname)
-                            .param(&channel_ref.name)
-                            .trailing(channel.topic())
-                            .build();
-                        send(rpl_topic);
-                    }
+                let password_valid = oper
+                    .is_some_and(|oper| bcrypt::verify(password, &oper.password).unwrap_or(false));
+                if !password_valid {
+                    Self::broadcast_server_notice(
+                        query,
+                        format!(
+                            "Failed OPER attempt by {} ({}) using name {}: password mismatch",
+                            nickname, user_mask, name
+                        ),
+                    );
+                    let err_passwdmismatch = MessageBuilder::new(ERR_PASSWDMISMATCH)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .trailing("Password incorrect")
+                        .build();
+                    send(err_passwdmismatch);
+                    return;
+                }
 
-                    // Inform other users of join
-                    let channel_users = query
-                        .channel_users(&channel_ref.name)
-                        .map(|users| users.iter().map(|user| user.client_id).collect_vec());
-                    if let Some(users) = channel_users {
-                        Action::JoinInform {
-                            channel: channel_ref.name.clone(),
-                        }
-                        .dispatch_multi_by_client_id(query, &users[..]);
-                    }
+                Self::broadcast_server_notice(
+                    query,
+                    format!(
+                        "{} ({}) is now an operator of class {}",
+                        nickname,
+                        user_mask,
+                        oper_class.unwrap_or_default()
+                    ),
+                );
+                let user = query.user_mut();
+                user.oper_name = Some(name.clone());
+                if !user.has_mode(UserMode::Operator) {
+                    user.modes.push(UserMode::Operator);
                 }
+
+                let rpl_youreoper = MessageBuilder::new(RPL_YOUREOPER)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing("You are now an IRC operator")
+                    .build();
+                let mode_command = MessageBuilder::new("MODE")
+                    .prefix(&nickname, None, None)
+                    .param(&nickname)
+                    .trailing("+o")
+                    .build();
+                send(rpl_youreoper);
+                send(mode_command);
             }
 
-            Action::JoinInform { channel } => {
+            Action::PermissionDenied { privilege } => {
+                send(Self::no_privileges_message(query, server_host, *privilege));
+            }
+
+            Action::ServerNotice { text } => {
                 let nickname = query.user().nickname.clone().unwrap();
-                let join_command = MessageBuilder::new("JOIN")
-                    .prefix(&nickname, None, None)
-                    .param(channel)
+                let notice = MessageBuilder::new("NOTICE")
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing(&format!("*** Notice -- {}", text))
                     .build();
-                send(join_command);
+                send(notice);
             }
 
-            Action::PrivateMessage {
-                message,
-                users,
-                channels,
-            } => {
+            Action::Die => {
                 let nickname = query.user().nickname.clone().unwrap();
+                Self::broadcast_server_notice(
+                    query,
+                    format!("{} is shutting down the server", nickname),
+                );
 
-                // Collect target user client IDs
-                let target_clients = users
+                // Close all client connections
+                let clients = query
+                    .server()
+                    .users
                     .iter()
-                    .flat_map(|nickname| {
-                        if let Some(user) = query.user_find_by_nickname(nickname) {
-                            Some(user.client_id)
-                        } else {
-                            None
-                        }
-                    })
+                    .map(|user| user.client_id)
                     .collect_vec();
-
-                // Dispatch private message to target clients
-                for target_client in target_clients {
-                    Action::PrivateMessageUser {
-                        message: message.clone(),
-                        from_nickname: nickname.clone(),
-                    }
-                    .dispatch_for_client_id(query, target_client);
+                let error_command = MessageBuilder::new("ERROR")
+                    .trailing(&format!(
+                        "Closing Link: Server shutting down ({})",
+                        nickname
+                    ))
+                    .build();
+                Action::Relay {
+                    message: error_command,
+                }
+                .dispatch_multi_by_client_id(query, &clients[..]);
+                for user in query.server().users.iter() {
+                    let _ = user.stream.shutdown(std::net::Shutdown::Both);
                 }
 
-                // Iterate over target channels
-                for channel_name in channels {
-                    // Collect clients in channel (except sender client)
-                    let clients = query.channel_find(&channel_name).map(|channel| {
-                        channel
-                            .clients()
-                            .iter()
-                            .filter(|target_client_id| client_id != **target_client_id)
-                            .map(|client_id| *client_id)
-                            .collect_vec()
-                    });
+                println!("[Server] DIE [client={}]", user_host);
+                std::process::exit(0);
+            }
 
-                    // Dispatch private message to all users of channel
-                    if let Some(clients) = clients {
-                        Action::PrivateMessageChannel {
-                            message: message.clone(),
-                            channel: channel_name.clone(),
-                            from_nickname: nickname.clone(),
+            Action::Rehash => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let rpl_rehashing = MessageBuilder::new(RPL_REHASHING)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(CONFIG_PATH)
+                    .trailing("Rehashing")
+                    .build();
+                send(rpl_rehashing);
+                let _ = writer.flush();
+
+                match query.server_mut().rehash() {
+                    Ok(changes) => Self::broadcast_server_notice(
+                        query,
+                        format!(
+                            "{} rehashed {} ({})",
+                            nickname,
+                            CONFIG_PATH,
+                            Self::describe_config_changes(&changes)
+                        ),
+                    ),
+                    Err(err) => {
+                        println!("[Server] Rehash failed: {}", err);
+                        Action::StandardReply {
+                            kind: ReplyKind::Fail,
+                            command: String::from("REHASH"),
+                            code: String::from("INVALID_CONFIG"),
+                            context: vec![],
+                            description: format!(
+                                "Unable to rehash, keeping current config: {}",
+                                err
+                            ),
                         }
-                        .dispatch_multi_by_client_id(query, &clients[..]);
+                        .dispatch_for_client_id(query, client_id);
                     }
                 }
             }
 
-            Action::PrivateMessageUser {
+            Action::AddBan {
+                kind,
+                mask,
+                duration,
+                reason,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let notice = |text: &str| {
+                    MessageBuilder::new("NOTICE")
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .trailing(&format!("*** Notice -- {}", text))
+                        .build()
+                };
+
+                // Ban the host of a connected user when given a nickname
+                let target = query
+                    .user_find_by_nickname(mask)
+                    .map(|user| (user.host.clone(), user.ip));
+                let mask = match (kind, target) {
+                    (BanKind::Dline, Some((_, ip))) => ip.to_string(),
+                    (_, Some((host, _))) => format!("*@{}", host),
+                    (BanKind::Kline, None) | (BanKind::Gline, None) if !mask.contains('@') => {
+                        format!("*@{}", mask)
+                    }
+                    _ => mask.clone(),
+                };
+                if let Some(problem) = Ban::mask_error(*kind, &mask) {
+                    send(notice(&format!(
+                        "Invalid {} [{}]: {}",
+                        kind.name(),
+                        mask,
+                        problem
+                    )));
+                    return;
+                }
+
+                let ban = Ban::new(
+                    *kind,
+                    mask.clone(),
+                    reason.clone(),
+                    nickname.clone(),
+                    *duration,
+                );
+                guard!(let Some(ban) = ban else {
+                    send(notice(&format!(
+                        "Invalid {} [{}]: Duration is too long",
+                        kind.name(),
+                        mask
+                    )));
+                    return;
+                });
+                let saved = query.server().bans_mut().add(ban.clone());
+                if let Err(err) = saved {
+                    println!("[Server] Unable to save bans: {}", err);
+                    Action::StandardReply {
+                        kind: ReplyKind::Warn,
+                        command: kind.command().to_string(),
+                        code: String::from("SAVE_FAILED"),
+                        context: vec![],
+                        description: format!("Unable to save ban database: {}", err),
+                    }
+                    .dispatch(query, writer);
+                }
+                Self::broadcast_server_notice(
+                    query,
+                    format!(
+                        "{} added {} {} for [{}] [{}]",
+                        nickname,
+                        Self::describe_ban_duration(*duration),
+                        kind.name(),
+                        mask,
+                        reason
+                    ),
+                );
+
+                // Disconnect users matching the new ban
+                let clients = query.banned_clients(&ban);
+                for target_client in clients {
+                    Action::Disconnect {
+                        reason: Self::ban_exit_reason(&ban),
+                    }
+                    .dispatch_for_client_id(query, target_client);
+                }
+            }
+
+            Action::RemoveBan { kind, mask } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let removed = query.server().bans_mut().remove(*kind, mask);
+                match removed {
+                    Ok(true) => Self::broadcast_server_notice(
+                        query,
+                        format!(
+                            "{} has removed the {} for [{}]",
+                            nickname,
+                            kind.name(),
+                            mask
+                        ),
+                    ),
+                    Ok(false) => {
+                        let notice = MessageBuilder::new("NOTICE")
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .trailing(&format!(
+                                "*** Notice -- No {} for [{}] found",
+                                kind.name(),
+                                mask
+                            ))
+                            .build();
+                        send(notice);
+                    }
+                    Err(err) => Action::StandardReply {
+                        kind: ReplyKind::Warn,
+                        command: format!("UN{}", kind.command()),
+                        code: String::from("SAVE_FAILED"),
+                        context: vec![],
+                        description: format!("Unable to save ban database: {}", err),
+                    }
+                    .dispatch(query, writer),
+                }
+            }
+
+            Action::Stats { letter } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let kind = [BanKind::Kline, BanKind::Dline, BanKind::Gline]
+                    .iter()
+                    .copied()
+                    .find(|kind| kind.stats_letter() == letter.to_ascii_lowercase());
+
+                if let Some(kind) = kind {
+                    let numeric = match kind {
+                        BanKind::Kline => RPL_STATSKLINE,
+                        BanKind::Dline => RPL_STATSDLINE,
+                        BanKind::Gline => RPL_STATSGLINE,
+                    };
+                    let bans = query
+                        .server()
+                        .bans()
+                        .list(kind)
+                        .into_iter()
+                        .cloned()
+                        .collect_vec();
+                    for ban in bans {
+                        let rpl_stats = MessageBuilder::new(numeric)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(&kind.stats_letter().to_ascii_uppercase().to_string())
+                            .param(&ban.mask)
+                            .trailing(&format!(
+                                "{} (set by {}, {})",
+                                ban.reason,
+                                ban.set_by,
+                                Self::describe_ban_duration(ban.remaining())
+                            ))
+                            .build();
+                        send(rpl_stats);
+                    }
+                } else if *letter == 'u' {
+                    let uptime = Utc::now() - query.server().startup_time;
+                    let rpl_statsuptime = MessageBuilder::new(RPL_STATSUPTIME)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .trailing(&format!(
+                            "Server Up {} days {}:{:02}:{:02}",
+                            uptime.num_days(),
+                            uptime.num_hours() % 24,
+                            uptime.num_minutes() % 60,
+                            uptime.num_seconds() % 60
+                        ))
+                        .build();
+                    send(rpl_statsuptime);
+                }
+
+                let rpl_endofstats = MessageBuilder::new(RPL_ENDOFSTATS)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(&letter.to_string())
+                    .trailing("End of /STATS report")
+                    .build();
+                send(rpl_endofstats);
+            }
+
+            Action::Lusers => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let user_count = query.registered_user_count();
+                let invisible_count = query.invisible_user_count();
+                let operator_count = query.operator_count();
+                let unknown_count = query.unknown_connection_count();
+                let channel_count = query.channel_count();
+                let max_user_count = query.max_user_count();
+
+                let rpl_luserclient = MessageBuilder::new(RPL_LUSERCLIENT)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing(&format!(
+                        "There are {} users and {} invisible on 1 servers",
+                        user_count - invisible_count,
+                        invisible_count
+                    ))
+                    .build();
+                let rpl_luserop = MessageBuilder::new(RPL_LUSEROP)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(&operator_count.to_string())
+                    .trailing("operator(s) online")
+                    .build();
+                let rpl_luserunknown = MessageBuilder::new(RPL_LUSERUNKNOWN)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(&unknown_count.to_string())
+                    .trailing("unknown connection(s)")
+                    .build();
+                let rpl_luserchannels = MessageBuilder::new(RPL_LUSERCHANNELS)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(&channel_count.to_string())
+                    .trailing("channels formed")
+                    .build();
+                let rpl_luserme = MessageBuilder::new(RPL_LUSERME)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing(&format!("I have {} clients and 0 servers", user_count))
+                    .build();
+                let rpl_localusers = MessageBuilder::new(RPL_LOCALUSERS)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(&user_count.to_string())
+                    .param(&max_user_count.to_string())
+                    .trailing(&format!(
+                        "Current local users {}, max {}",
+                        user_count, max_user_count
+                    ))
+                    .build();
+                let rpl_globalusers = MessageBuilder::new(RPL_GLOBALUSERS)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(&user_count.to_string())
+                    .param(&max_user_count.to_string())
+                    .trailing(&format!(
+                        "Current global users {}, max {}",
+                        user_count, max_user_count
+                    ))
+                    .build();
+                send(rpl_luserclient);
+                if operator_count > 0 {
+                    send(rpl_luserop);
+                }
+                if unknown_count > 0 {
+                    send(rpl_luserunknown);
+                }
+                if channel_count > 0 {
+                    send(rpl_luserchannels);
+                }
+                send(rpl_luserme);
+                send(rpl_localusers);
+                send(rpl_globalusers);
+            }
+
+            Action::Version => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let rpl_version = MessageBuilder::new(RPL_VERSION)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(&format!("myriad-{}", SOFTWARE_VERSION))
+                    .param(server_host)
+                    .trailing("An experimental IRC server written in Rust")
+                    .build();
+                send(rpl_version);
+                let tokens = ISupport::new(query.server_config()).tokens();
+                for rpl_isupport in Self::isupport_messages(server_host, &nickname, &tokens) {
+                    send(rpl_isupport);
+                }
+            }
+
+            Action::Time => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let now = Local::now();
+                let rpl_time = MessageBuilder::new(RPL_TIME)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(server_host)
+                    .param(&now.timestamp().to_string())
+                    .trailing(&now.to_rfc2822())
+                    .build();
+                send(rpl_time);
+            }
+
+            Action::Admin => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let admin = &query.server_config().admin;
+                let rpl_adminme = MessageBuilder::new(RPL_ADMINME)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(server_host)
+                    .trailing("Administrative info")
+                    .build();
+                let rpl_adminloc1 = MessageBuilder::new(RPL_ADMINLOC1)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing(&admin.location)
+                    .build();
+                let rpl_adminloc2 = MessageBuilder::new(RPL_ADMINLOC2)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing(&admin.description)
+                    .build();
+                let rpl_adminemail = MessageBuilder::new(RPL_ADMINEMAIL)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing(&admin.email)
+                    .build();
+                send(rpl_adminme);
+                send(rpl_adminloc1);
+                send(rpl_adminloc2);
+                send(rpl_adminemail);
+            }
+
+            Action::Info => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let info = [
+                    format!("Myriad IRCd {}", SOFTWARE_VERSION),
+                    String::from("An experimental IRC server written in Rust."),
+                    String::new(),
+                    format!("Written by {}", env!("CARGO_PKG_AUTHORS")),
+                    String::new(),
+                    format!("Online since {}", query.server_startup_time()),
+                ];
+                for line in info.iter() {
+                    let rpl_info = MessageBuilder::new(RPL_INFO)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .trailing(line)
+                        .build();
+                    send(rpl_info);
+                }
+                let rpl_endofinfo = MessageBuilder::new(RPL_ENDOFINFO)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing("End of /INFO list")
+                    .build();
+                send(rpl_endofinfo);
+            }
+
+            Action::Join { channels } => {
+                let nickname = query.user().nickname.clone().unwrap();
+
+                for channel_ref in channels {
+                    // Validate channel name
+                    let channellen = query.server_config().feat_channellen as usize;
+                    if !channel_ref.name.starts_with(['#', '&'])
+                        || channel_ref.name.len() > channellen
+                    {
+                        let err_badchanmask = MessageBuilder::new(ERR_BADCHANMASK)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(&channel_ref.name)
+                            .trailing("Bad Channel Mask")
+                            .build();
+                        send(err_badchanmask);
+                        continue;
+                    }
+
+                    // Ignore channels the user is already in
+                    let already_joined = query
+                        .channel_find(&channel_ref.name)
+                        .is_some_and(|channel| channel.clients().contains(&client_id));
+                    if already_joined {
+                        continue;
+                    }
+
+                    // Enforce channel limit
+                    let chanlimit = query.server_config().feat_chanlimit as usize;
+                    if query.channels_of_client(client_id).len() >= chanlimit {
+                        let err_toomanychannels = MessageBuilder::new(ERR_TOOMANYCHANNELS)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(&channel_ref.name)
+                            .trailing("You have joined too many channels")
+                            .build();
+                        send(err_toomanychannels);
+                        continue;
+                    }
+
+                    // Create channel if it doesn't exist
+                    let channel = query.channel_get_or_create(&channel_ref.name);
+
+                    // The first user of a channel becomes its operator
+                    if channel.clients().is_empty() {
+                        channel.add_mode(ChannelMode::Op {
+                            nickname: nickname.clone(),
+                        });
+                    }
+
+                    // Join client into channel
+                    channel.join_user(client_id);
+
+                    // Send topic
+                    if !channel.topic().is_empty() {
+                        let rpl_topic = MessageBuilder::new(RPL_TOPIC)
+                            .param(&nickname)
+                            .param(&channel_ref.name)
+                            .trailing(channel.topic())
+                            .build();
+                        send(rpl_topic);
+                        if let (Some(set_by), Some(set_at)) =
+                            (channel.topic_set_by(), channel.topic_set_at())
+                        {
+                            let rpl_topicwhotime = MessageBuilder::new(RPL_TOPICWHOTIME)
+                                .param(&nickname)
+                                .param(&channel_ref.name)
+                                .param(set_by)
+                                .param(&set_at.timestamp().to_string())
+                                .build();
+                            send(rpl_topicwhotime);
+                        }
+                    }
+
+                    // Inform other users of join
+                    let channel_users = query
+                        .channel_users(&channel_ref.name)
+                        .map(|users| users.iter().map(|user| user.client_id).collect_vec());
+                    if let Some(users) = channel_users {
+                        let user = query.user();
+                        Action::JoinInform {
+                            channel: channel_ref.name.clone(),
+                            nickname: nickname.clone(),
+                            username: user.username.clone().unwrap(),
+                            host: visible_host.clone(),
+                            account: user.account.clone(),
+                            realname: user.realname.clone().unwrap_or_default(),
+                            tags: query.event_tags(),
+                        }
+                        .dispatch_multi_by_client_id(query, &users[..]);
+                    }
+
+                    // List the members
+                    for message in
+                        Self::names_messages(query, server_host, &nickname, &channel_ref.name)
+                    {
+                        send(message);
+                    }
+                }
+            }
+
+            Action::JoinInform {
+                channel,
+                nickname,
+                username,
+                host,
+                account,
+                realname,
+                tags,
+            } => {
+                let join_command = Self::join_message(
+                    query.user(),
+                    channel,
+                    (nickname, username, host),
+                    account.as_deref(),
+                    realname,
+                );
+                send(Self::tagged_message(query.user(), &join_command, tags));
+            }
+
+            Action::PrivateMessage {
+                kind,
+                message,
+                lines,
+                users,
+                channels,
+                tags,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let mut tags = tags.clone();
+                tags.extend(&query.event_tags());
+
+                // Collect target user client IDs
+                let target_clients = users
+                    .iter()
+                    .flat_map(|nickname| {
+                        if let Some(user) = query.user_find_by_nickname(nickname) {
+                            Some(user.client_id)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect_vec();
+
+                // Targets the message is delivered to, echoed back to the sender
+                let echo_targets = users
+                    .iter()
+                    .filter(|nickname| query.user_find_by_nickname(nickname).is_some())
+                    .chain(
+                        channels
+                            .iter()
+                            .filter(|channel| query.channel_find(channel).is_some()),
+                    )
+                    .cloned()
+                    .collect_vec();
+
+                // Inform sender about away targets, NOTICE and TAGMSG must not trigger replies
+                let away_targets = target_clients
+                    .iter()
+                    .filter(|_| *kind == MessageKind::Privmsg)
+                    .flat_map(|target_client| query.user_find_by_client_id(*target_client))
+                    .flat_map(|user| Some((user.nickname.clone()?, user.away.clone()?)))
+                    .collect_vec();
+                for (target_nickname, away_message) in away_targets {
+                    let rpl_away = MessageBuilder::new(RPL_AWAY)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(&target_nickname)
+                        .trailing(&away_message)
+                        .build();
+                    send(rpl_away);
+                }
+
+                // Dispatch private message to target clients
+                for target_client in target_clients {
+                    Action::PrivateMessageUser {
+                        kind: *kind,
+                        message: message.clone(),
+                        lines: lines.clone(),
+                        from_nickname: nickname.clone(),
+                        tags: tags.clone(),
+                    }
+                    .dispatch_for_client_id(query, target_client);
+                }
+
+                // Iterate over target channels
+                for channel_name in channels {
+                    // Collect clients in channel (except sender client)
+                    let clients = query.channel_find(&channel_name).map(|channel| {
+                        channel
+                            .clients()
+                            .iter()
+                            .filter(|target_client_id| client_id != **target_client_id)
+                            .map(|client_id| *client_id)
+                            .collect_vec()
+                    });
+
+                    // Dispatch private message to all users of channel
+                    if let Some(clients) = clients {
+                        Action::PrivateMessageChannel {
+                            kind: *kind,
+                            message: message.clone(),
+                            lines: lines.clone(),
+                            channel: channel_name.clone(),
+                            from_nickname: nickname.clone(),
+                            tags: tags.clone(),
+                        }
+                        .dispatch_multi_by_client_id(query, &clients[..]);
+                    }
+                }
+
+                // Keep PRIVMSG and NOTICE in the history of their targets
+                if *kind != MessageKind::Tagmsg {
+                    for target in echo_targets.iter() {
+                        let mut entry = HistoryEntry::new(
+                            String::new(),
+                            *kind,
+                            &nickname,
+                            target,
+                            message,
+                            lines,
+                            &tags,
+                        );
+                        if let Some(channel) = query.channel_find(target) {
+                            // Only members' messages are kept, as only they can read them back
+                            if !channel.clients().contains(&client_id) {
+                                continue;
+                            }
+                            entry.key = HistoryEntry::channel_key(target);
+                        } else {
+                            let target_identity = query
+                                .user_find_by_nickname(target)
+                                .and_then(|user| query.history_identity(user.client_id));
+                            entry.key = HistoryEntry::conversation_key(&nickname, target);
+                            entry.from_identity = query.history_identity(client_id).unwrap();
+                            entry.target_identity = target_identity.unwrap_or_default();
+                        }
+                        let server = query.server_mut();
+                        if let Err(err) = server.history.add(entry, &server.config.history) {
+                            println!("[Server] Unable to write message history: {}", err);
+                        }
+                    }
+                }
+
+                // Confirm delivery to senders that negotiated echo-message
+                if query.user().has_capability(Capability::EchoMessage) {
+                    for target in echo_targets {
+                        let echo = Self::relayed_messages(
+                            query, *kind, &nickname, &target, message, lines, &tags,
+                        );
+                        for message in echo {
+                            send(message);
+                        }
+                    }
+                }
+            }
+
+            Action::PrivateMessageUser {
+                kind,
                 message,
+                lines,
                 from_nickname,
+                tags,
             } => {
                 let nickname = query.user().nickname.clone().unwrap();
-                let privmsg_command = MessageBuilder::new("PRIVMSG")
-                    .prefix(&from_nickname, None, None)
+                let privmsg_commands = Self::relayed_messages(
+                    query,
+                    *kind,
+                    from_nickname,
+                    &nickname,
+                    message,
+                    lines,
+                    tags,
+                );
+                for privmsg_command in privmsg_commands {
+                    send(privmsg_command);
+                }
+            }
+
+            Action::PrivateMessageChannel {
+                kind,
+                message,
+                lines,
+                channel,
+                from_nickname,
+                tags,
+            } => {
+                let privmsg_commands = Self::relayed_messages(
+                    query,
+                    *kind,
+                    from_nickname,
+                    channel,
+                    message,
+                    lines,
+                    tags,
+                );
+                for privmsg_command in privmsg_commands {
+                    send(privmsg_command);
+                }
+            }
+
+            Action::MultilineStart {
+                reference,
+                target,
+                tags,
+            } => {
+                // Batches can't be nested, both are discarded
+                if query.user_mut().multiline.take().is_some() {
+                    Self::multiline_fail(
+                        "MULTILINE_INVALID",
+                        vec![],
+                        "A multiline batch is already open",
+                    )
+                    .dispatch(query, writer);
+                    return;
+                }
+                query.user_mut().multiline = Some(MultilineBatch {
+                    reference: reference.clone(),
+                    target: target.clone(),
+                    kind: None,
+                    lines: vec![],
+                    tags: tags.clone(),
+                    rejected: false,
+                });
+            }
+
+            Action::MultilineLine {
+                reference,
+                kind,
+                target,
+                text,
+                concat,
+            } => {
+                let batch = query
+                    .user()
+                    .multiline
+                    .clone()
+                    .filter(|batch| batch.reference == *reference);
+                guard!(let Some(mut batch) = batch else {
+                    Self::multiline_fail(
+                        "MULTILINE_INVALID",
+                        vec![reference.clone()],
+                        "Unknown batch reference",
+                    )
+                    .dispatch(query, writer);
+                    return;
+                });
+                // Lines of rejected batches are dropped until the batch ends
+                if batch.rejected {
+                    return;
+                }
+
+                let mut lines = batch.lines.clone();
+                lines.push(MultilineLine {
+                    text: text.clone(),
+                    concat: *concat,
+                });
+                let failure = if *kind == MessageKind::Tagmsg
+                    || batch.kind.is_some_and(|batch_kind| batch_kind != *kind)
+                {
+                    Some((
+                        "MULTILINE_INVALID",
+                        vec![],
+                        "Multiline batches may only contain either PRIVMSG or NOTICE",
+                    ))
+                } else if !target.eq_ignore_ascii_case(&batch.target) {
+                    Some((
+                        "MULTILINE_INVALID_TARGET",
+                        vec![batch.target.clone(), target.clone()],
+                        "Messages must be sent to the target of the batch",
+                    ))
+                } else if *concat && text.is_empty() {
+                    Some((
+                        "MULTILINE_INVALID",
+                        vec![],
+                        "Concatenated lines must not be blank",
+                    ))
+                } else if lines.len() > MULTILINE_MAX_LINES {
+                    Some((
+                        "MULTILINE_MAX_LINES",
+                        vec![MULTILINE_MAX_LINES.to_string()],
+                        "Multiline batch has too many lines",
+                    ))
+                } else if MultilineLine::join(&lines).len() > MULTILINE_MAX_BYTES {
+                    Some((
+                        "MULTILINE_MAX_BYTES",
+                        vec![MULTILINE_MAX_BYTES.to_string()],
+                        "Multiline batch is too long",
+                    ))
+                } else {
+                    None
+                };
+
+                match failure {
+                    Some((code, context, description)) => {
+                        batch.rejected = true;
+                        query.user_mut().multiline = Some(batch);
+                        Self::multiline_fail(code, context, description).dispatch(query, writer);
+                    }
+                    None => {
+                        batch.kind = Some(*kind);
+                        batch.lines = lines;
+                        query.user_mut().multiline = Some(batch);
+                    }
+                }
+            }
+
+            Action::MultilineEnd { reference } => {
+                let batch = query
+                    .user_mut()
+                    .multiline
+                    .take_if(|batch| batch.reference == *reference);
+                guard!(let Some(batch) = batch else {
+                    Self::multiline_fail(
+                        "MULTILINE_INVALID",
+                        vec![reference.clone()],
+                        "Unknown batch reference",
+                    )
+                    .dispatch(query, writer);
+                    return;
+                });
+                if batch.rejected {
+                    return;
+                }
+                let kind = batch
+                    .kind
+                    .filter(|_| !MultilineLine::fallback(&batch.lines).is_empty());
+                guard!(let Some(kind) = kind else {
+                    Self::multiline_fail("MULTILINE_INVALID", vec![], "Multiline batch is empty")
+                        .dispatch(query, writer);
+                    return;
+                });
+
+                let is_channel = batch.target.starts_with(['#', '&']);
+                Action::PrivateMessage {
+                    kind,
+                    message: MultilineLine::join(&batch.lines),
+                    lines: batch.lines,
+                    users: if is_channel {
+                        vec![]
+                    } else {
+                        vec![batch.target.clone()]
+                    },
+                    channels: if is_channel {
+                        vec![batch.target]
+                    } else {
+                        vec![]
+                    },
+                    tags: batch.tags,
+                }
+                .dispatch(query, writer);
+            }
+
+            Action::ChatHistory {
+                target,
+                selector,
+                limit,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+
+                // Channel history is only available to members
+                let key = if let Some(channel) = query.channel_find(target) {
+                    if !channel.clients().contains(&client_id) {
+                        Action::StandardReply {
+                            kind: ReplyKind::Fail,
+                            command: String::from("CHATHISTORY"),
+                            code: String::from("INVALID_TARGET"),
+                            context: vec![target.clone()],
+                            description: String::from("You are not on that channel"),
+                        }
+                        .dispatch(query, writer);
+                        return;
+                    }
+                    HistoryEntry::channel_key(target)
+                } else {
+                    HistoryEntry::conversation_key(&nickname, target)
+                };
+
+                let identity = query.history_identity(client_id).unwrap();
+                let server = query.server();
+                let entries = server
+                    .history
+                    .select(
+                        &key,
+                        selector,
+                        *limit,
+                        |entry| entry.is_visible_to(&nickname, &identity),
+                        &server.config.history,
+                    )
+                    .into_iter()
+                    .cloned()
+                    .collect_vec();
+                let mut messages = vec![];
+                for entry in entries {
+                    messages.extend(Self::relayed_messages(
+                        query,
+                        entry.kind,
+                        &entry.from_nickname,
+                        &entry.target,
+                        &entry.text,
+                        &entry.lines,
+                        &entry.tags(),
+                    ));
+                }
+                Action::Batch {
+                    batch_type: String::from("chathistory"),
+                    params: vec![target.clone()],
+                    tags: MessageTags::default(),
+                    messages,
+                }
+                .dispatch(query, writer);
+            }
+
+            Action::ChatHistoryTargets { between, limit } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let channels = query
+                    .channels_of_client(client_id)
+                    .iter()
+                    .map(|channel| HistoryEntry::channel_key(channel.name()))
+                    .collect_vec();
+                let identity = query.history_identity(client_id).unwrap();
+                let include = |entry: &HistoryEntry| {
+                    if HistoryEntry::is_channel_key(&entry.key) {
+                        channels.contains(&entry.key)
+                    } else {
+                        entry.is_visible_to(&nickname, &identity)
+                    }
+                };
+
+                let server = query.server();
+                let messages = server
+                    .history
+                    .targets(include, *between, *limit, &server.config.history)
+                    .into_iter()
+                    .map(|entry| {
+                        MessageBuilder::new("CHATHISTORY")
+                            .prefix(server_host, None, None)
+                            .param("TARGETS")
+                            .param(entry.target_for(&nickname))
+                            .param(&format_time(entry.time))
+                            .build()
+                    })
+                    .collect_vec();
+                Action::Batch {
+                    batch_type: String::from("draft/chathistory-targets"),
+                    params: vec![],
+                    tags: MessageTags::default(),
+                    messages,
+                }
+                .dispatch(query, writer);
+            }
+
+            Action::Away { message } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                println!(
+                    "[Server] AWAY [client={}, message='{}']",
+                    user_host,
+                    message.as_ref().unwrap_or(&String::new())
+                );
+                query.user_mut().away = message.clone();
+
+                let reply = match message {
+                    Some(_) => MessageBuilder::new(RPL_NOWAWAY)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .trailing("You have been marked as being away"),
+                    None => MessageBuilder::new(RPL_UNAWAY)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .trailing("You are no longer marked as being away"),
+                };
+                send(reply.build());
+
+                // Inform channel peers that negotiated away-notify
+                let away_notify = Action::AwayNotify {
+                    nickname,
+                    username: query.user().username.clone().unwrap(),
+                    host: visible_host.clone(),
+                    message: message.clone(),
+                    tags: query.event_tags(),
+                };
+                let peers = query
+                    .channel_peers(client_id)
+                    .into_iter()
+                    .filter(|peer| {
+                        query
+                            .user_find_by_client_id(*peer)
+                            .is_some_and(|user| user.has_capability(Capability::AwayNotify))
+                    })
+                    .collect_vec();
+                away_notify.dispatch_multi_by_client_id(query, &peers[..]);
+            }
+
+            Action::AwayNotify {
+                nickname,
+                username,
+                host,
+                message,
+                tags,
+            } => {
+                let mut away_command =
+                    MessageBuilder::new("AWAY").prefix(nickname, Some(username), Some(host));
+                if let Some(message) = message {
+                    away_command = away_command.trailing(message);
+                }
+                send(Self::tagged_message(
+                    query.user(),
+                    &away_command.build(),
+                    tags,
+                ));
+            }
+
+            Action::Authenticate { data } => {
+                let nickname = query.user().nickname_or_star();
+                let sasl_reply = |code: &str, text: &str| {
+                    MessageBuilder::new(code)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .trailing(text)
+                        .build()
+                };
+                let sasl_fail = sasl_reply(ERR_SASLFAIL, "SASL authentication failed");
+
+                if !query.user().has_capability(Capability::Sasl) {
+                    send(sasl_fail);
+                    return;
+                }
+                if data == "*" {
+                    match query.user_mut().sasl_buffer.take() {
+                        Some(_) => send(sasl_reply(ERR_SASLABORTED, "SASL authentication aborted")),
+                        None => send(sasl_fail),
+                    }
+                    return;
+                }
+
+                // The first message selects the mechanism, only PLAIN is supported
+                guard!(let Some(mut buffer) = query.user_mut().sasl_buffer.take() else {
+                    // Authenticating again logs out of the current account,
+                    // dropping its vhost
+                    let mut vhost_dropped = false;
+                    if let Some(account) = query.user_mut().account.take() {
+                        let username = query
+                            .user()
+                            .username
+                            .clone()
+                            .unwrap_or_else(|| String::from("*"));
+                        let rpl_loggedout = MessageBuilder::new(RPL_LOGGEDOUT)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(&format!("{}!{}@{}", nickname, username, visible_host))
+                            .trailing("You are now logged out")
+                            .build();
+                        send(rpl_loggedout);
+                        println!(
+                            "[Server] LOGOUT [client={}, account={}]",
+                            user_host, account
+                        );
+                        Self::inform_account_notify(query, client_id, "*");
+                        vhost_dropped = query.user_mut().vhost.take().is_some();
+                    }
+                    if data.eq_ignore_ascii_case("PLAIN") {
+                        query.user_mut().sasl_buffer = Some(String::new());
+                        send(MessageBuilder::new("AUTHENTICATE").param("+").build());
+                    } else {
+                        let rpl_saslmechs = MessageBuilder::new(RPL_SASLMECHS)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param("PLAIN")
+                            .trailing("are available SASL mechanisms")
+                            .build();
+                        send(rpl_saslmechs);
+                        send(sasl_fail);
+                    }
+                    if vhost_dropped {
+                        Action::HostChange {
+                            old_host: visible_host.clone(),
+                        }
+                        .dispatch(query, writer);
+                    }
+                    return;
+                });
+                if data.len() > SASL_CHUNK_LEN || buffer.len() + data.len() > SASL_MAX_LEN {
+                    send(sasl_reply(ERR_SASLTOOLONG, "SASL message too long"));
+                    return;
+                }
+
+                // Data is sent in chunks, a shorter chunk (or `+`) ends it
+                if data != "+" {
+                    buffer.push_str(data);
+                }
+                if data.len() == SASL_CHUNK_LEN {
+                    query.user_mut().sasl_buffer = Some(buffer);
+                    return;
+                }
+
+                // PLAIN: <authzid> NUL <authcid> NUL <password>
+                let credentials = BASE64
+                    .decode(&buffer)
+                    .ok()
+                    .and_then(|credentials| String::from_utf8(credentials).ok());
+                let account = credentials.and_then(|credentials| {
+                    let (authzid, authcid, password) = credentials.split('\0').collect_tuple()?;
+                    if !authzid.is_empty() && authzid != authcid {
+                        return None;
+                    }
+                    query
+                        .server_config()
+                        .find_account(authcid)
+                        .filter(|account| {
+                            bcrypt::verify(password, &account.password).unwrap_or(false)
+                        })
+                        .map(|account| (account.name.clone(), account.vhost.clone()))
+                });
+                println!(
+                    "[Server] AUTHENTICATE [client={}, account={}]",
+                    user_host,
+                    account.as_ref().map_or("*", |(name, _)| name.as_str())
+                );
+                guard!(let Some((account, vhost)) = account else {
+                    send(sasl_fail);
+                    return;
+                });
+
+                query.user_mut().account = Some(account.clone());
+                if vhost.is_some() {
+                    query.user_mut().vhost = vhost;
+                }
+                let user = query.user();
+                let username = user.username.clone().unwrap_or_else(|| String::from("*"));
+                let rpl_loggedin = MessageBuilder::new(RPL_LOGGEDIN)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(&format!("{}!{}@{}", nickname, username, visible_host))
+                    .param(&account)
+                    .trailing(&format!("You are now logged in as {}", account))
+                    .build();
+                send(rpl_loggedin);
+                send(sasl_reply(
+                    RPL_SASLSUCCESS,
+                    "SASL authentication successful",
+                ));
+
+                Self::inform_account_notify(query, client_id, &account);
+
+                // Apply the account's vhost
+                Action::HostChange {
+                    old_host: visible_host.clone(),
+                }
+                .dispatch(query, writer);
+            }
+
+            Action::MonitorAdd { targets } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let limit = query.server_config().feat_monitor as usize;
+
+                // Targets are added until the list is full
+                let mut added = vec![];
+                for (index, target) in targets.iter().enumerate() {
+                    let key = target.to_ascii_lowercase();
+                    if query.user().monitor.contains(&key) {
+                        continue;
+                    }
+                    if query.user().monitor.len() >= limit {
+                        let err_monlistfull = MessageBuilder::new(ERR_MONLISTFULL)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(&limit.to_string())
+                            .param(&targets[index..].join(","))
+                            .trailing("Monitor list is full.")
+                            .build();
+                        send(err_monlistfull);
+                        break;
+                    }
+                    query.user_mut().monitor.push(key);
+                    added.push(target.clone());
+                }
+                let (online, offline) = Self::monitor_status(query, &added);
+                let replies =
+                    Self::monitor_messages(server_host, &nickname, RPL_MONONLINE, &online)
+                        .into_iter()
+                        .chain(Self::monitor_messages(
+                            server_host,
+                            &nickname,
+                            RPL_MONOFFLINE,
+                            &offline,
+                        ));
+                for reply in replies {
+                    send(reply);
+                }
+            }
+
+            Action::MonitorRemove { targets } => {
+                let targets = targets
+                    .iter()
+                    .map(|target| target.to_ascii_lowercase())
+                    .collect_vec();
+                query
+                    .user_mut()
+                    .monitor
+                    .retain(|key| !targets.contains(key));
+            }
+
+            Action::MonitorClear => {
+                query.user_mut().monitor.clear();
+            }
+
+            Action::MonitorList => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let targets = query.user().monitor.clone();
+                for rpl_monlist in
+                    Self::monitor_messages(server_host, &nickname, RPL_MONLIST, &targets)
+                {
+                    send(rpl_monlist);
+                }
+                let rpl_endofmonlist = MessageBuilder::new(RPL_ENDOFMONLIST)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing("End of MONITOR list")
+                    .build();
+                send(rpl_endofmonlist);
+            }
+
+            Action::MonitorStatus => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let (online, offline) = Self::monitor_status(query, &query.user().monitor);
+                let replies =
+                    Self::monitor_messages(server_host, &nickname, RPL_MONONLINE, &online)
+                        .into_iter()
+                        .chain(Self::monitor_messages(
+                            server_host,
+                            &nickname,
+                            RPL_MONOFFLINE,
+                            &offline,
+                        ));
+                for reply in replies {
+                    send(reply);
+                }
+            }
+
+            Action::MonitorInform {
+                nickname: target_nickname,
+                mask,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let (code, target) = match mask {
+                    Some(mask) => (RPL_MONONLINE, mask),
+                    None => (RPL_MONOFFLINE, target_nickname),
+                };
+                let reply = MessageBuilder::new(code)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing(target)
+                    .build();
+                send(reply);
+            }
+
+            Action::Ison { nicknames } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let online = nicknames
+                    .iter()
+                    .flat_map(|target| query.user_find_by_nickname(target))
+                    .filter(|user| user.registered)
+                    .flat_map(|user| user.nickname.clone())
+                    .join(" ");
+                let rpl_ison = MessageBuilder::new(RPL_ISON)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing(&online)
+                    .build();
+                send(rpl_ison);
+            }
+
+            Action::Userhost { nicknames } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let see_hidden = query.user_has_privilege(Privilege::SeeHidden);
+
+                // <nickname>[*]=<+|-><username>@<host>, `*` marks operators and `-` away users
+                let replies = nicknames
+                    .iter()
+                    .flat_map(|target| query.user_find_by_nickname(target))
+                    .filter(|user| user.registered)
+                    .map(|user| {
+                        // Only the user and operators may see the real host
+                        let host = if see_hidden || user.client_id == client_id {
+                            &user.host
+                        } else {
+                            user.visible_host()
+                        };
+                        format!(
+                            "{}{}={}{}@{}",
+                            user.nickname.clone().unwrap(),
+                            if user.has_mode(UserMode::Operator) {
+                                "*"
+                            } else {
+                                ""
+                            },
+                            if user.away.is_some() { "-" } else { "+" },
+                            user.username.clone().unwrap(),
+                            host
+                        )
+                    })
+                    .join(" ");
+                let rpl_userhost = MessageBuilder::new(RPL_USERHOST)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing(&replies)
+                    .build();
+                send(rpl_userhost);
+            }
+
+            Action::SetName { realname } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let username = query.user().username.clone().unwrap();
+                println!(
+                    "[Server] SETNAME [client={}, realname='{}']",
+                    user_host, realname
+                );
+                query.user_mut().realname = Some(realname.clone());
+
+                // Inform the user and channel peers that negotiated setname
+                let setname_command = MessageBuilder::new("SETNAME")
+                    .prefix(&nickname, Some(&username), Some(&visible_host))
+                    .trailing(realname)
+                    .build();
+                let tags = query.event_tags();
+                if query.user().has_capability(Capability::Setname) {
+                    send(Self::tagged_message(query.user(), &setname_command, &tags));
+                }
+                let peers = query
+                    .channel_peers(client_id)
+                    .into_iter()
+                    .filter(|peer| {
+                        query
+                            .user_find_by_client_id(*peer)
+                            .is_some_and(|user| user.has_capability(Capability::Setname))
+                    })
+                    .collect_vec();
+                Action::RelayEvent {
+                    message: setname_command,
+                    tags,
+                }
+                .dispatch_multi_by_client_id(query, &peers[..]);
+            }
+
+            Action::ChgHost {
+                nickname: target_nickname,
+                host,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+
+                guard!(let Some(target_client) = query.user_find_by_nickname(target_nickname).map(|user| user.client_id) else {
+                    let err_nosuchnick = MessageBuilder::new(ERR_NOSUCHNICK)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(target_nickname)
+                        .trailing("No such nick/channel")
+                        .build();
+                    send(err_nosuchnick);
+                    return;
+                });
+                if !User::is_valid_host(host) {
+                    Action::StandardReply {
+                        kind: ReplyKind::Fail,
+                        command: String::from("CHGHOST"),
+                        code: String::from("INVALID_PARAMS"),
+                        context: vec![],
+                        description: String::from("Invalid host"),
+                    }
+                    .dispatch(query, writer);
+                    return;
+                }
+
+                let old_host = match query.user_find_by_client_id_mut(target_client) {
+                    Some(target) => {
+                        let old_host = target.visible_host().to_string();
+                        target.vhost = Some(host.clone());
+                        old_host
+                    }
+                    None => return,
+                };
+                let description = format!("Changed the host of {} to {}", target_nickname, host);
+                Action::StandardReply {
+                    kind: ReplyKind::Note,
+                    command: String::from("CHGHOST"),
+                    code: String::from("HOST_CHANGED"),
+                    context: vec![target_nickname.clone(), host.clone()],
+                    description,
+                }
+                .dispatch(query, writer);
+                Self::broadcast_server_notice(
+                    query,
+                    format!(
+                        "{} changed the host of {} to {}",
+                        nickname, target_nickname, host
+                    ),
+                );
+                Action::HostChange { old_host }.dispatch_for_client_id(query, target_client);
+            }
+
+            Action::HostChange { old_host } => {
+                if !query.user().registered || visible_host == *old_host {
+                    return;
+                }
+                let nickname = query.user().nickname.clone().unwrap();
+                let username = query.user().username.clone().unwrap();
+                send(Self::host_hidden_message(query, server_host));
+
+                let tags = query.event_tags();
+                if query.user().has_capability(Capability::Chghost) {
+                    let chghost_command = MessageBuilder::new("CHGHOST")
+                        .prefix(&nickname, Some(&username), Some(old_host))
+                        .param(&username)
+                        .param(&visible_host)
+                        .build();
+                    send(Self::tagged_message(query.user(), &chghost_command, &tags));
+                }
+                let peers = query.channel_peers(client_id);
+                Action::HostChangeInform {
+                    client_id,
+                    old_host: old_host.clone(),
+                    tags,
+                }
+                .dispatch_multi_by_client_id(query, &peers[..]);
+            }
+
+            Action::HostChangeInform {
+                client_id: changed_client_id,
+                old_host,
+                tags,
+            } => {
+                guard!(let Some(user) = query.user_find_by_client_id(*changed_client_id) else {
+                    return;
+                });
+                let nickname = user.nickname.clone().unwrap();
+                let username = user.username.clone().unwrap();
+                let host = user.visible_host();
+
+                if query.user().has_capability(Capability::Chghost) {
+                    let chghost_command = MessageBuilder::new("CHGHOST")
+                        .prefix(&nickname, Some(&username), Some(old_host))
+                        .param(&username)
+                        .param(host)
+                        .build();
+                    send(Self::tagged_message(query.user(), &chghost_command, tags));
+                    return;
+                }
+
+                // Simulate the change by quitting and rejoining the shared channels
+                let quit_command = MessageBuilder::new("QUIT")
+                    .prefix(&nickname, Some(&username), Some(old_host))
+                    .trailing("Changing host")
+                    .build();
+                send(Self::tagged_message(query.user(), &quit_command, tags));
+                let shared_channels = query
+                    .channels_of_client(*changed_client_id)
+                    .into_iter()
+                    .filter(|channel| channel.clients().contains(&client_id));
+                for channel in shared_channels {
+                    send(Self::join_message(
+                        query.user(),
+                        channel.name(),
+                        (&nickname, &username, host),
+                        user.account.as_deref(),
+                        user.realname.as_deref().unwrap_or_default(),
+                    ));
+                    if channel.is_op(&nickname) {
+                        let mode_command = MessageBuilder::new("MODE")
+                            .prefix(server_host, None, None)
+                            .param(channel.name())
+                            .param("+o")
+                            .param(&nickname)
+                            .build();
+                        send(mode_command);
+                    }
+                }
+                if let Some(away_message) = &user.away {
+                    if query.user().has_capability(Capability::AwayNotify) {
+                        let away_command = MessageBuilder::new("AWAY")
+                            .prefix(&nickname, Some(&username), Some(host))
+                            .trailing(away_message)
+                            .build();
+                        send(away_command);
+                    }
+                }
+            }
+
+            Action::AccountNotify {
+                nickname,
+                username,
+                host,
+                account,
+                tags,
+            } => {
+                let account_command = MessageBuilder::new("ACCOUNT")
+                    .prefix(nickname, Some(username), Some(host))
+                    .param(account)
+                    .build();
+                send(Self::tagged_message(query.user(), &account_command, tags));
+            }
+
+            Action::Who { mask } => {
+                let nickname = query.user().nickname.clone().unwrap();
+
+                // Collect matching users along with the channel they were found in
+                let is_channel = mask.starts_with(['#', '&']);
+                let users = if is_channel {
+                    query.channel_users(mask).unwrap_or_default()
+                } else {
+                    query.user_find_by_nickname(mask).into_iter().collect_vec()
+                };
+                let channel = if is_channel { mask.as_ref() } else { "*" };
+                let who_replies = users
+                    .iter()
+                    .map(|user| {
+                        let flag = if user.away.is_some() { "G" } else { "H" };
+                        MessageBuilder::new(RPL_WHOREPLY)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(channel)
+                            .param(user.username.as_deref().unwrap_or("*"))
+                            .param(user.visible_host())
+                            .param(server_host)
+                            .param(user.nickname.as_deref().unwrap_or("*"))
+                            .param(flag)
+                            .trailing(&format!(
+                                "0 {}",
+                                user.realname.as_deref().unwrap_or_default()
+                            ))
+                            .build()
+                    })
+                    .collect_vec();
+
+                for who_reply in who_replies {
+                    send(who_reply);
+                }
+                let rpl_endofwho = MessageBuilder::new(RPL_ENDOFWHO)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(mask)
+                    .trailing("End of WHO list")
+                    .build();
+                send(rpl_endofwho);
+            }
+
+            Action::Whois {
+                nickname: target_nickname,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+
+                if let Some(target) = query.user_find_by_nickname(target_nickname) {
+                    let rpl_whoisuser = MessageBuilder::new(RPL_WHOISUSER)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(target_nickname)
+                        .param(target.username.as_deref().unwrap_or("*"))
+                        .param(target.visible_host())
+                        .param("*")
+                        .trailing(target.realname.as_deref().unwrap_or_default())
+                        .build();
+                    let see_hidden = query.user_has_privilege(Privilege::SeeHidden);
+                    // Only the user and operators may see the real host
+                    let rpl_whoishost = (see_hidden || target.client_id == client_id).then(|| {
+                        MessageBuilder::new(RPL_WHOISHOST)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(target_nickname)
+                            .trailing(&format!(
+                                "is connecting from *@{} {}",
+                                target.host, target.ip
+                            ))
+                            .build()
+                    });
+                    let channels = query
+                        .channels_of_client(target.client_id)
+                        .iter()
+                        .filter(|channel| {
+                            !channel.is_hidden()
+                                || see_hidden
+                                || channel.clients().contains(&client_id)
+                        })
+                        .map(|channel| channel.name())
+                        .join(" ");
+                    let rpl_whoischannels = MessageBuilder::new(RPL_WHOISCHANNELS)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(target_nickname)
+                        .trailing(&channels)
+                        .build();
+                    let rpl_whoisserver = MessageBuilder::new(RPL_WHOISSERVER)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(target_nickname)
+                        .param(server_host)
+                        .trailing(query.server_name())
+                        .build();
+                    let rpl_away = target.away.as_ref().map(|away_message| {
+                        MessageBuilder::new(RPL_AWAY)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(target_nickname)
+                            .trailing(away_message)
+                            .build()
+                    });
+                    send(rpl_whoisuser);
+                    if let Some(rpl_whoishost) = rpl_whoishost {
+                        send(rpl_whoishost);
+                    }
+                    if !channels.is_empty() {
+                        send(rpl_whoischannels);
+                    }
+                    send(rpl_whoisserver);
+                    if let Some(rpl_away) = rpl_away {
+                        send(rpl_away);
+                    }
+                } else {
+                    let err_nosuchnick = MessageBuilder::new(ERR_NOSUCHNICK)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(target_nickname)
+                        .trailing("No such nick/channel")
+                        .build();
+                    send(err_nosuchnick);
+                }
+
+                let rpl_endofwhois = MessageBuilder::new(RPL_ENDOFWHOIS)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .param(target_nickname)
+                    .trailing("End of /WHOIS list")
+                    .build();
+                send(rpl_endofwhois);
+            }
+
+            Action::List { filters } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let see_hidden = query.user_has_privilege(Privilege::SeeHidden);
+
+                // Any of the positive masks may match, all other filters must accept
+                let masks = filters
+                    .iter()
+                    .filter_map(|filter| match filter {
+                        ListFilter::Mask(mask) => Some(mask),
+                        _ => None,
+                    })
+                    .collect_vec();
+                let entries = query
+                    .channels()
+                    .iter()
+                    .filter(|channel| {
+                        !channel.is_hidden() || see_hidden || channel.clients().contains(&client_id)
+                    })
+                    .filter(|channel| {
+                        masks.is_empty()
+                            || masks.iter().any(|mask| mask::matches(mask, channel.name()))
+                    })
+                    .map(|channel| {
+                        let user_count = query
+                            .channel_users(channel.name())
+                            .unwrap_or_default()
+                            .len();
+                        (channel, user_count)
+                    })
+                    .filter(|(channel, user_count)| {
+                        filters
+                            .iter()
+                            .all(|filter| filter.accepts(channel, *user_count))
+                    })
+                    .map(|(channel, user_count)| {
+                        MessageBuilder::new(RPL_LIST)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(channel.name())
+                            .param(&user_count.to_string())
+                            .trailing(channel.topic())
+                            .build()
+                    })
+                    .collect_vec();
+
+                let rpl_liststart = MessageBuilder::new(RPL_LISTSTART)
+                    .prefix(server_host, None, None)
                     .param(&nickname)
-                    .trailing(&message)
+                    .param("Channel")
+                    .trailing("Users  Name")
                     .build();
-                send(privmsg_command);
+                send(rpl_liststart);
+                for entry in entries {
+                    send(entry);
+                }
+                let rpl_listend = MessageBuilder::new(RPL_LISTEND)
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing("End of /LIST")
+                    .build();
+                send(rpl_listend);
             }
 
-            Action::PrivateMessageChannel {
-                message,
-                channel,
-                from_nickname,
+            Action::ChannelMode {
+                channel: channel_name,
+                changes,
+                params,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let username = query.user().username.clone().unwrap();
+                let can_override = query.user_has_privilege(Privilege::Override);
+
+                guard!(let Some(channel) = query.channel_find_mut(channel_name) else {
+                    let err_nosuchchannel = MessageBuilder::new(ERR_NOSUCHCHANNEL)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(channel_name)
+                        .trailing("No such channel")
+                        .build();
+                    send(err_nosuchchannel);
+                    return;
+                });
+
+                // Reply with the current modes
+                if changes.is_empty() {
+                    let rpl_channelmodeis = MessageBuilder::new(RPL_CHANNELMODEIS)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(channel_name)
+                        .param(&channel.mode_string())
+                        .build();
+                    let rpl_creationtime = MessageBuilder::new(RPL_CREATIONTIME)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(channel_name)
+                        .param(&channel.created_at().timestamp().to_string())
+                        .build();
+                    send(rpl_channelmodeis);
+                    send(rpl_creationtime);
+                    return;
+                }
+
+                if !channel.is_op(&nickname) && !can_override {
+                    let err_chanoprivsneeded = MessageBuilder::new(ERR_CHANOPRIVSNEEDED)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(channel_name)
+                        .trailing("You're not channel operator")
+                        .build();
+                    send(err_chanoprivsneeded);
+                    return;
+                }
+
+                // Prefix modes take the nickname of a channel member as parameter
+                let max_changes = query.server_config().feat_modes as usize;
+                let mut params = params.iter();
+                let mut resolved = vec![];
+                for (adding, letter) in changes.iter().take(max_changes) {
+                    let is_prefix_mode = ChannelMode::prefix_letters()
+                        .iter()
+                        .any(|(prefix_letter, _)| prefix_letter == letter);
+                    if !is_prefix_mode {
+                        resolved.push((*adding, *letter, None));
+                        continue;
+                    }
+                    guard!(let Some(target) = params.next() else { continue });
+                    let member = query
+                        .user_find_by_nickname(target)
+                        .map(|user| (user.client_id, user.nickname.clone().unwrap()));
+                    let is_member = |target_client| {
+                        query
+                            .channel_find(channel_name)
+                            .is_some_and(|channel| channel.clients().contains(&target_client))
+                    };
+                    match member {
+                        Some((target_client, target_nickname)) if is_member(target_client) => {
+                            resolved.push((*adding, *letter, Some(target_nickname)));
+                        }
+                        Some(_) => {
+                            let err_usernotinchannel = MessageBuilder::new(ERR_USERNOTINCHANNEL)
+                                .prefix(server_host, None, None)
+                                .param(&nickname)
+                                .param(target)
+                                .param(channel_name)
+                                .trailing("They aren't on that channel")
+                                .build();
+                            send(err_usernotinchannel);
+                        }
+                        None => {
+                            let err_nosuchnick = MessageBuilder::new(ERR_NOSUCHNICK)
+                                .prefix(server_host, None, None)
+                                .param(&nickname)
+                                .param(target)
+                                .trailing("No such nick/channel")
+                                .build();
+                            send(err_nosuchnick);
+                        }
+                    }
+                }
+
+                // Apply mode changes
+                let mut applied = String::new();
+                let mut applied_params = vec![];
+                let mut unknown = vec![];
+                guard!(let Some(channel) = query.channel_find_mut(channel_name) else { return });
+                for (adding, letter, target) in resolved {
+                    let mode = match &target {
+                        Some(target) => ChannelMode::from_prefix_letter(letter, target),
+                        None => ChannelMode::from_letter(letter),
+                    };
+                    guard!(let Some(mode) = mode else {
+                        unknown.push(letter);
+                        continue;
+                    });
+                    if adding == channel.has_mode(&mode) {
+                        continue;
+                    }
+                    if adding {
+                        channel.add_mode(mode);
+                    } else {
+                        channel.remove_mode(&mode);
+                    }
+                    applied.push(if adding { '+' } else { '-' });
+                    applied.push(letter);
+                    applied_params.extend(target);
+                }
+                for letter in unknown {
+                    let err_unknownmode = MessageBuilder::new(ERR_UNKNOWNMODE)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(&letter.to_string())
+                        .trailing("is unknown mode char to me")
+                        .build();
+                    send(err_unknownmode);
+                }
+
+                // Inform channel members of the change
+                if !applied.is_empty() {
+                    let clients = channel.clients().to_vec();
+                    let mode_command = applied_params
+                        .iter()
+                        .fold(
+                            MessageBuilder::new("MODE")
+                                .prefix(&nickname, Some(&username), Some(&visible_host))
+                                .param(channel_name)
+                                .param(&applied),
+                            |mode_command, param| mode_command.param(param),
+                        )
+                        .build();
+                    Action::RelayEvent {
+                        message: mode_command,
+                        tags: query.event_tags(),
+                    }
+                    .dispatch_multi_by_client_id(query, &clients[..]);
+                }
+            }
+
+            Action::UserMode {
+                nickname: target_nickname,
+                changes,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+
+                if *target_nickname != nickname {
+                    let err_usersdontmatch = MessageBuilder::new(ERR_USERSDONTMATCH)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .trailing("Cant change mode for other users")
+                        .build();
+                    send(err_usersdontmatch);
+                    return;
+                }
+
+                // Reply with the current modes
+                if changes.is_empty() {
+                    let rpl_umodeis = MessageBuilder::new(RPL_UMODEIS)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(&query.user().mode_string())
+                        .build();
+                    send(rpl_umodeis);
+                    return;
+                }
+
+                // Apply mode changes
+                let mut applied = String::new();
+                let mut unknown_flag = false;
+                let mut missing_privilege = None;
+                let max_changes = query.server_config().feat_modes as usize;
+                for (adding, letter) in changes.iter().take(max_changes) {
+                    guard!(let Some(mode) = UserMode::from_letter(*letter) else {
+                        unknown_flag = true;
+                        continue;
+                    });
+                    if *adding == query.user().has_mode(mode)
+                        || (*adding && !mode.is_user_settable())
+                    {
+                        continue;
+                    }
+                    // Cloaking can't be enabled when it's disabled on the server
+                    if mode == UserMode::Cloaked && query.user().cloaked_host.is_none() {
+                        continue;
+                    }
+                    if let Some(privilege) = mode.required_privilege().filter(|_| *adding) {
+                        if !query.user_has_privilege(privilege) {
+                            missing_privilege = Some(privilege);
+                            continue;
+                        }
+                    }
+                    let user = query.user_mut();
+                    if *adding {
+                        user.modes.push(mode);
+                    } else {
+                        user.modes.retain(|m| *m != mode);
+                    }
+                    applied.push(if *adding { '+' } else { '-' });
+                    applied.push(*letter);
+
+                    // Dropping operator status also drops modes that require privileges
+                    if mode == UserMode::Operator {
+                        user.oper_name = None;
+                        let privileged_modes = user
+                            .modes
+                            .iter()
+                            .filter(|mode| mode.required_privilege().is_some())
+                            .copied()
+                            .collect_vec();
+                        for privileged_mode in privileged_modes {
+                            user.modes.retain(|m| *m != privileged_mode);
+                            applied.push('-');
+                            applied.push(privileged_mode.letter());
+                        }
+                    }
+                }
+                if let Some(privilege) = missing_privilege {
+                    send(Self::no_privileges_message(query, server_host, privilege));
+                }
+                if unknown_flag {
+                    let err_umodeunknownflag = MessageBuilder::new(ERR_UMODEUNKNOWNFLAG)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .trailing("Unknown MODE flag")
+                        .build();
+                    send(err_umodeunknownflag);
+                }
+                if !applied.is_empty() {
+                    let mode_command = MessageBuilder::new("MODE")
+                        .prefix(&nickname, None, None)
+                        .param(&nickname)
+                        .trailing(&applied)
+                        .build();
+                    send(mode_command);
+                }
+                if applied.contains('x') {
+                    Action::HostChange {
+                        old_host: visible_host.clone(),
+                    }
+                    .dispatch(query, writer);
+                }
+            }
+
+            Action::Topic {
+                channel: channel_name,
+                topic,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let username = query.user().username.clone().unwrap();
+
+                guard!(let Some(channel) = query.channel_find_mut(channel_name) else {
+                    let err_nosuchchannel = MessageBuilder::new(ERR_NOSUCHCHANNEL)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(channel_name)
+                        .trailing("No such channel")
+                        .build();
+                    send(err_nosuchchannel);
+                    return;
+                });
+
+                if !channel.clients().contains(&client_id) {
+                    let err_notonchannel = MessageBuilder::new(ERR_NOTONCHANNEL)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(channel_name)
+                        .trailing("You're not on that channel")
+                        .build();
+                    send(err_notonchannel);
+                    return;
+                }
+
+                guard!(let Some(topic) = topic else {
+                    // Reply with the current topic
+                    if channel.topic().is_empty() {
+                        let rpl_notopic = MessageBuilder::new(RPL_NOTOPIC)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(channel_name)
+                            .trailing("No topic is set")
+                            .build();
+                        send(rpl_notopic);
+                    } else {
+                        let rpl_topic = MessageBuilder::new(RPL_TOPIC)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(channel_name)
+                            .trailing(channel.topic())
+                            .build();
+                        send(rpl_topic);
+                        if let (Some(set_by), Some(set_at)) =
+                            (channel.topic_set_by(), channel.topic_set_at())
+                        {
+                            let rpl_topicwhotime = MessageBuilder::new(RPL_TOPICWHOTIME)
+                                .prefix(server_host, None, None)
+                                .param(&nickname)
+                                .param(channel_name)
+                                .param(set_by)
+                                .param(&set_at.timestamp().to_string())
+                                .build();
+                            send(rpl_topicwhotime);
+                        }
+                    }
+                    return;
+                });
+
+                // Set the topic and inform channel members
+                channel.set_topic(topic.clone(), nickname.clone());
+                let clients = channel.clients().to_vec();
+                let topic_command = MessageBuilder::new("TOPIC")
+                    .prefix(&nickname, Some(&username), Some(&visible_host))
+                    .param(channel_name)
+                    .trailing(topic)
+                    .build();
+                Action::RelayEvent {
+                    message: topic_command,
+                    tags: query.event_tags(),
+                }
+                .dispatch_multi_by_client_id(query, &clients[..]);
+            }
+
+            Action::Names { channels } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let channels = if channels.is_empty() {
+                    vec![String::from("*")]
+                } else {
+                    channels.clone()
+                };
+                for channel in channels {
+                    for message in Self::names_messages(query, server_host, &nickname, &channel) {
+                        send(message);
+                    }
+                }
+            }
+
+            Action::Kick {
+                channel: channel_name,
+                users,
+                comment,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let username = query.user().username.clone().unwrap();
+                let can_override = query.user_has_privilege(Privilege::Override);
+
+                guard!(let Some(channel) = query.channel_find(channel_name) else {
+                    let err_nosuchchannel = MessageBuilder::new(ERR_NOSUCHCHANNEL)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(channel_name)
+                        .trailing("No such channel")
+                        .build();
+                    send(err_nosuchchannel);
+                    return;
+                });
+                if !channel.clients().contains(&client_id) {
+                    let err_notonchannel = MessageBuilder::new(ERR_NOTONCHANNEL)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(channel_name)
+                        .trailing("You're not on that channel")
+                        .build();
+                    send(err_notonchannel);
+                    return;
+                }
+                if !channel.is_op(&nickname) && !can_override {
+                    let err_chanoprivsneeded = MessageBuilder::new(ERR_CHANOPRIVSNEEDED)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(channel_name)
+                        .trailing("You're not channel operator")
+                        .build();
+                    send(err_chanoprivsneeded);
+                    return;
+                }
+
+                for target in users {
+                    let clients = query
+                        .channel_find(channel_name)
+                        .map(|channel| channel.clients().to_vec())
+                        .unwrap_or_default();
+                    let member = query
+                        .user_find_by_nickname(target)
+                        .map(|user| (user.client_id, user.nickname.clone().unwrap()))
+                        .filter(|(target_client, _)| clients.contains(target_client));
+                    guard!(let Some((target_client, target_nickname)) = member else {
+                        let err_usernotinchannel = MessageBuilder::new(ERR_USERNOTINCHANNEL)
+                            .prefix(server_host, None, None)
+                            .param(&nickname)
+                            .param(target)
+                            .param(channel_name)
+                            .trailing("They aren't on that channel")
+                            .build();
+                        send(err_usernotinchannel);
+                        continue;
+                    });
+
+                    // Inform all members including the kicked user, then remove them
+                    let kick_command = MessageBuilder::new("KICK")
+                        .prefix(&nickname, Some(&username), Some(&visible_host))
+                        .param(channel_name)
+                        .param(&target_nickname)
+                        .trailing(comment.as_deref().unwrap_or(&nickname))
+                        .build();
+                    Action::RelayEvent {
+                        message: kick_command,
+                        tags: query.event_tags(),
+                    }
+                    .dispatch_multi_by_client_id(query, &clients[..]);
+                    println!(
+                        "[Server] KICK [client={}, channel={}, target={}]",
+                        user_host, channel_name, target_nickname
+                    );
+                    guard!(let Some(channel) = query.channel_find_mut(channel_name) else { return });
+                    channel.part_user(target_client, Some(&target_nickname));
+                }
+
+                query.channel_remove_empty();
+            }
+
+            Action::Relay { message } => {
+                send(message.clone());
+            }
+
+            Action::RelayEvent { message, tags } => {
+                send(Self::tagged_message(query.user(), message, tags));
+            }
+
+            Action::Batch {
+                batch_type,
+                params,
+                tags,
+                messages,
             } => {
-                let privmsg_command = MessageBuilder::new("PRIVMSG")
-                    .prefix(&from_nickname, None, None)
-                    .param(channel)
-                    .trailing(&message)
+                if !query.user().has_capability(Capability::Batch) {
+                    for message in messages {
+                        send(message.clone());
+                    }
+                    return;
+                }
+
+                let reference = query.batch_reference();
+                let start_reference = format!("+{}", reference);
+                let mut batch_start = MessageBuilder::new("BATCH")
+                    .prefix(server_host, None, None)
+                    .param(&start_reference)
+                    .param(batch_type);
+                for param in params {
+                    batch_start = batch_start.param(param);
+                }
+                send(Self::with_tags(&batch_start.build(), tags));
+
+                // Messages of nested batches keep their own batch tag
+                let mut batch_tags = MessageTags::default();
+                batch_tags.insert("batch", &reference);
+                for message in messages {
+                    send(Self::with_tags(message, &batch_tags));
+                }
+
+                let batch_end = MessageBuilder::new("BATCH")
+                    .prefix(server_host, None, None)
+                    .param(&format!("-{}", reference))
                     .build();
-                send(privmsg_command);
+                send(batch_end);
+            }
+
+            Action::LabeledResponse { label, responses } => {
+                let mut tags = MessageTags::default();
+                tags.insert("label", label);
+                match &responses[..] {
+                    [] => {
+                        let ack = MessageBuilder::new("ACK")
+                            .prefix(server_host, None, None)
+                            .build();
+                        send(Self::with_tags(&ack, &tags));
+                    }
+                    [response] => send(Self::with_tags(response, &tags)),
+                    _ => Action::Batch {
+                        batch_type: String::from("labeled-response"),
+                        params: vec![],
+                        tags,
+                        messages: responses.clone(),
+                    }
+                    .dispatch(query, writer),
+                }
             }
 
             Action::Quit { reason } => {
+                let reason = match reason {
+                    Some(reason) if !reason.is_empty() => format!("Quit: {}", reason),
+                    _ => String::from("Client Quit"),
+                };
+                Action::Disconnect { reason }.dispatch(query, writer);
+            }
+
+            Action::Disconnect { reason } => {
                 let user = query.user();
-                let nickname = user.nickname.clone().unwrap_or_default();
+                let nickname = user.nickname.clone();
+                let username = user.username.clone().unwrap_or_default();
+                let registered = user.registered;
+
+                // Inform the client before closing the link
+                let error_command = MessageBuilder::new("ERROR")
+                    .trailing(&format!("Closing Link: {} ({})", user_host, reason))
+                    .build();
+                send(error_command);
+                Self::send_labeled_response(query, client_id, writer);
+                let _ = writer.flush();
+
+                // Inform channel peers, only registered users can have any
+                if let (true, Some(nickname)) = (registered, &nickname) {
+                    let peers = query.channel_peers(client_id);
+                    let quit_command = MessageBuilder::new("QUIT")
+                        .prefix(nickname, Some(&username), Some(&visible_host))
+                        .trailing(reason)
+                        .build();
+                    Action::RelayEvent {
+                        message: quit_command,
+                        tags: query.event_tags(),
+                    }
+                    .dispatch_multi_by_client_id(query, &peers[..]);
+                    Self::inform_monitors(query, nickname, None);
+                }
+
+                // Leave all channels, channels without users cease to exist
+                for channel in query.server_mut().channels.iter_mut() {
+                    channel.part_user(client_id, nickname.as_deref());
+                }
+                query.channel_remove_empty();
 
                 // Terminate client
-                if user.stream.shutdown(std::net::Shutdown::Both).is_ok() {
+                if query
+                    .user()
+                    .stream
+                    .shutdown(std::net::Shutdown::Both)
+                    .is_ok()
+                {
                     println!(
-                        "[Server] Terminated connection of {nickname}@{host} (QUIT: {reason})",
-                        nickname = nickname,
+                        "[Server] Terminated connection of {nickname}@{host} ({reason})",
+                        nickname = nickname.as_deref().unwrap_or_default(),
                         host = user_host,
-                        reason = reason.as_ref().unwrap_or(&String::new())
+                        reason = reason
                     );
                 }
 
@@ -361,23 +3102,522 @@ impl Action {
                 let result = server_mut
                     .users
                     .iter()
-                    .position(|user| user.client_id == user.client_id)
+                    .position(|user| user.client_id == client_id)
                     .map(|index| server_mut.users.swap_remove(index));
                 if result.is_some() {
                     println!(
                         "[Server] Removed {nickname}@{host} from client list",
-                        nickname = nickname,
+                        nickname = nickname.as_deref().unwrap_or_default(),
                         host = user_host
                     )
                 }
             }
 
+            Action::Kill {
+                nickname: target_nickname,
+                reason,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+
+                guard!(let Some(target_client) = query.user_find_by_nickname(target_nickname).map(|user| user.client_id) else {
+                    let err_nosuchnick = MessageBuilder::new(ERR_NOSUCHNICK)
+                        .prefix(server_host, None, None)
+                        .param(&nickname)
+                        .param(target_nickname)
+                        .trailing("No such nick/channel")
+                        .build();
+                    send(err_nosuchnick);
+                    return;
+                });
+
+                Self::broadcast_server_notice(
+                    query,
+                    format!(
+                        "Received KILL message for {} from {} ({})",
+                        target_nickname, nickname, reason
+                    ),
+                );
+                Action::Disconnect {
+                    reason: format!("Killed ({} ({}))", nickname, reason),
+                }
+                .dispatch_for_client_id(query, target_client);
+            }
+
+            Action::Wallops { message } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let username = query.user().username.clone().unwrap();
+                let wallops_command = MessageBuilder::new("WALLOPS")
+                    .prefix(&nickname, Some(&username), Some(&visible_host))
+                    .trailing(message)
+                    .build();
+                let recipients = query
+                    .server()
+                    .users
+                    .iter()
+                    .filter(|user| user.has_mode(UserMode::Wallops))
+                    .map(|user| user.client_id)
+                    .collect_vec();
+                Action::RelayEvent {
+                    message: wallops_command,
+                    tags: query.event_tags(),
+                }
+                .dispatch_multi_by_client_id(query, &recipients[..]);
+            }
+
+            Action::Globops { message } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let recipients = query
+                    .server()
+                    .users
+                    .iter()
+                    .map(|user| user.client_id)
+                    .filter(|client_id| query.client_has_privilege(*client_id, Privilege::Globops))
+                    .collect_vec();
+                Action::Globop {
+                    from_nickname: nickname,
+                    message: message.clone(),
+                }
+                .dispatch_multi_by_client_id(query, &recipients[..]);
+            }
+
+            Action::Globop {
+                from_nickname,
+                message,
+            } => {
+                let nickname = query.user().nickname.clone().unwrap();
+                let notice = MessageBuilder::new("NOTICE")
+                    .prefix(server_host, None, None)
+                    .param(&nickname)
+                    .trailing(&format!(
+                        "*** Global -- from {}: {}",
+                        from_nickname, message
+                    ))
+                    .build();
+                send(notice);
+            }
+
             Action::Error { code } => {
                 let message = MessageBuilder::new(code)
                     .prefix(server_host, None, Some(&user_host))
                     .build();
                 send(message);
             }
+
+            Action::StandardReply {
+                kind,
+                command,
+                code,
+                context,
+                description,
+            } => {
+                if !query.user().has_capability(Capability::StandardReplies) {
+                    let notice = MessageBuilder::new("NOTICE")
+                        .prefix(server_host, None, None)
+                        .param(&query.user().nickname_or_star())
+                        .trailing(&format!("*** Notice -- {}: {}", command, description))
+                        .build();
+                    send(notice);
+                    return;
+                }
+
+                let mut reply = MessageBuilder::new(kind.command())
+                    .prefix(server_host, None, None)
+                    .param(command)
+                    .param(code);
+                // Empty parameters can't be sent before the trailing one
+                for param in context.iter().filter(|param| !param.is_empty()) {
+                    reply = reply.param(param);
+                }
+                send(reply.trailing(description).build());
+            }
+        }
+    }
+
+    /// Build a PRIVMSG, NOTICE or TAGMSG for a recipient. TAGMSG is only
+    /// sent to clients that negotiated `message-tags`.
+    fn relayed_message(
+        recipient: &User,
+        kind: MessageKind,
+        from_nickname: &str,
+        target: &str,
+        message: &str,
+        tags: &MessageTags,
+    ) -> Option<Message> {
+        if kind == MessageKind::Tagmsg && !recipient.has_capability(Capability::MessageTags) {
+            return None;
+        }
+
+        let mut builder = MessageBuilder::new(kind.command())
+            .prefix(from_nickname, None, None)
+            .param(target);
+        if kind != MessageKind::Tagmsg {
+            builder = builder.trailing(message);
+        }
+        Some(Self::tagged_message(recipient, &builder.build(), tags))
+    }
+
+    /// Build the lines relaying a message to the recipient. Multiline messages are
+    /// sent as a `draft/multiline` batch to clients that negotiated it, and line by
+    /// line to others, with only the first line carrying the message ID.
+    fn relayed_messages(
+        query: &mut ServerQuery,
+        kind: MessageKind,
+        from_nickname: &str,
+        target: &str,
+        message: &str,
+        lines: &[MultilineLine],
+        tags: &MessageTags,
+    ) -> Vec<Message> {
+        let recipient = query.user();
+        if lines.is_empty() {
+            return Self::relayed_message(recipient, kind, from_nickname, target, message, tags)
+                .into_iter()
+                .collect_vec();
+        }
+        if !recipient.has_capability(Capability::Multiline)
+            || !recipient.has_capability(Capability::Batch)
+        {
+            let line_tags = tags.filtered(|key| key != "msgid");
+            return MultilineLine::fallback(lines)
+                .iter()
+                .enumerate()
+                .flat_map(|(index, line)| {
+                    let tags = if index == 0 { tags } else { &line_tags };
+                    Self::relayed_message(recipient, kind, from_nickname, target, line, tags)
+                })
+                .collect_vec();
+        }
+
+        let reference = query.batch_reference();
+        let batch_start = MessageBuilder::new("BATCH")
+            .prefix(from_nickname, None, None)
+            .param(&format!("+{}", reference))
+            .param("draft/multiline")
+            .param(target)
+            .build();
+        let mut messages = vec![Self::tagged_message(query.user(), &batch_start, tags)];
+        for line in lines {
+            let mut line_tags = MessageTags::default();
+            line_tags.insert("batch", &reference);
+            if line.concat {
+                line_tags.insert("draft/multiline-concat", "");
+            }
+            let message = MessageBuilder::new(kind.command())
+                .prefix(from_nickname, None, None)
+                .param(target)
+                .trailing(&line.text)
+                .build();
+            messages.push(Self::with_tags(&message, &line_tags));
+        }
+        let batch_end = MessageBuilder::new("BATCH")
+            .prefix(from_nickname, None, None)
+            .param(&format!("-{}", reference))
+            .build();
+        messages.push(batch_end);
+        messages
+    }
+
+    /// FAIL reply to an invalid `draft/multiline` batch.
+    fn multiline_fail(code: &str, context: Vec<String>, description: &str) -> Action {
+        Action::StandardReply {
+            kind: ReplyKind::Fail,
+            command: String::from("BATCH"),
+            code: code.to_string(),
+            context,
+            description: description.to_string(),
+        }
+    }
+
+    /// Add the tags the recipient negotiated capabilities for to a message.
+    fn tagged_message(recipient: &User, message: &Message, tags: &MessageTags) -> Message {
+        let tags = tags.filtered(|key| recipient.has_capability(Capability::for_tag(key)));
+        Self::with_tags(message, &tags)
+    }
+
+    /// Add tags to a message, keeping the values of tags it already has.
+    fn with_tags(message: &Message, tags: &MessageTags) -> Message {
+        let raw = message.to_string();
+        let (existing_tags, rest) = MessageTags::split(&raw);
+        let mut tags = tags.clone();
+        if let Some(existing_tags) = existing_tags {
+            tags.extend(&MessageTags::parse(existing_tags));
+        }
+        if tags.is_empty() {
+            message.clone()
+        } else {
+            Message::from(format!("@{} {}", tags, rest))
+        }
+    }
+
+    /// Build a JOIN of a user identified by `(nickname, username, host)`,
+    /// including the account and realname for clients that negotiated `extended-join`.
+    fn join_message(
+        recipient: &User,
+        channel: &str,
+        (nickname, username, host): (&str, &str, &str),
+        account: Option<&str>,
+        realname: &str,
+    ) -> Message {
+        let mut join_command = MessageBuilder::new("JOIN")
+            .prefix(nickname, Some(username), Some(host))
+            .param(channel);
+        if recipient.has_capability(Capability::ExtendedJoin) {
+            join_command = join_command
+                .param(account.unwrap_or("*"))
+                .trailing(realname);
+        }
+        join_command.build()
+    }
+
+    /// Build RPL_NAMREPLY lines listing the members of a channel along with
+    /// their prefixes, followed by RPL_ENDOFNAMES. Hidden channels are only
+    /// listed to their members.
+    fn names_messages(
+        query: &ServerQuery,
+        server_host: &str,
+        nickname: &str,
+        channel_name: &str,
+    ) -> Vec<Message> {
+        let mut messages = vec![];
+        let channel = query.channel_find(channel_name).filter(|channel| {
+            !channel.is_hidden() || channel.clients().contains(&query.user().client_id)
+        });
+        if let Some(channel) = channel {
+            let symbol = if channel.has_mode(&ChannelMode::Secret) {
+                "@"
+            } else if channel.has_mode(&ChannelMode::Private) {
+                "*"
+            } else {
+                "="
+            };
+            let names = query
+                .channel_users(channel.name())
+                .unwrap_or_default()
+                .iter()
+                .flat_map(|user| user.nickname.as_deref())
+                .map(|member| format!("{}{}", channel.prefix(member), member))
+                .collect_vec();
+            let mut lines: Vec<String> = vec![];
+            for name in names {
+                match lines.last_mut() {
+                    Some(line) if line.len() + name.len() < NAMES_LINE_LEN => {
+                        line.push(' ');
+                        line.push_str(&name);
+                    }
+                    _ => lines.push(name),
+                }
+            }
+            for line in lines {
+                let rpl_namreply = MessageBuilder::new(RPL_NAMREPLY)
+                    .prefix(server_host, None, None)
+                    .param(nickname)
+                    .param(symbol)
+                    .param(channel.name())
+                    .trailing(&line)
+                    .build();
+                messages.push(rpl_namreply);
+            }
+        }
+        let rpl_endofnames = MessageBuilder::new(RPL_ENDOFNAMES)
+            .prefix(server_host, None, None)
+            .param(nickname)
+            .param(channel_name)
+            .trailing("End of /NAMES list")
+            .build();
+        messages.push(rpl_endofnames);
+        messages
+    }
+
+    /// Build an RPL_HOSTHIDDEN reply with the host now shown to other users.
+    fn host_hidden_message(query: &ServerQuery, server_host: &str) -> Message {
+        let user = query.user();
+        MessageBuilder::new(RPL_HOSTHIDDEN)
+            .prefix(server_host, None, None)
+            .param(&user.nickname_or_star())
+            .param(user.visible_host())
+            .trailing("is now your displayed host")
+            .build()
+    }
+
+    /// Send the welcome sequence once NICK and USER were received
+    /// and capability negotiation (if any) has ended.
+    fn try_complete_registration(query: &mut ServerQuery, writer: &mut ClientWriter) {
+        let user = query.user();
+        if !user.registered
+            && !user.cap_negotiating
+            && user.nickname.is_some()
+            && user.username.is_some()
+        {
+            // Reject banned users
+            let username = user.username.clone().unwrap_or_default();
+            let ban = query
+                .server()
+                .bans()
+                .find(&username, &user.hosts(), user.ip)
+                .cloned();
+            if let Some(ban) = ban {
+                let err_yourebannedcreep = MessageBuilder::new(ERR_YOUREBANNEDCREEP)
+                    .prefix(query.server_host(), None, None)
+                    .param(&user.nickname_or_star())
+                    .trailing(&format!("You are banned from this server ({})", ban.reason))
+                    .build();
+                Action::Relay {
+                    message: err_yourebannedcreep,
+                }
+                .dispatch(query, writer);
+                Action::Disconnect {
+                    reason: Self::ban_exit_reason(&ban),
+                }
+                .dispatch(query, writer);
+                return;
+            }
+
+            Action::SendWelcomeSequence.dispatch(query, writer);
+        }
+    }
+
+    /// Quit reason for connections closed due to a ban.
+    fn ban_exit_reason(ban: &Ban) -> String {
+        format!("{}d ({})", ban.kind.name(), ban.reason)
+    }
+
+    /// Describe how long a ban lasts, e.g. `temporary 30 min.` or `permanent`.
+    fn describe_ban_duration(seconds: Option<i64>) -> String {
+        match seconds {
+            Some(seconds) => format!(
+                "temporary {} min.",
+                seconds / 60 + i64::from(seconds % 60 > 0)
+            ),
+            None => String::from("permanent"),
+        }
+    }
+
+    /// Build RPL_ISUPPORT lines for the given tokens.
+    fn isupport_messages(server_host: &str, nickname: &str, tokens: &[String]) -> Vec<Message> {
+        ISupport::split_lines(tokens)
+            .iter()
+            .map(|line| {
+                let mut rpl_isupport = MessageBuilder::new(RPL_ISUPPORT)
+                    .prefix(server_host, None, None)
+                    .param(nickname);
+                for token in line.iter() {
+                    rpl_isupport = rpl_isupport.param(token);
+                }
+                rpl_isupport
+                    .trailing("are supported by this server")
+                    .build()
+            })
+            .collect_vec()
+    }
+
+    /// Build MONITOR replies listing `targets`, split into lines of a reasonable length.
+    fn monitor_messages(
+        server_host: &str,
+        nickname: &str,
+        code: &str,
+        targets: &[String],
+    ) -> Vec<Message> {
+        let mut lines: Vec<String> = vec![];
+        for target in targets {
+            match lines.last_mut() {
+                Some(line) if line.len() + target.len() < MONITOR_LINE_LEN => {
+                    line.push(',');
+                    line.push_str(target);
+                }
+                _ => lines.push(target.clone()),
+            }
+        }
+        lines
+            .iter()
+            .map(|line| {
+                MessageBuilder::new(code)
+                    .prefix(server_host, None, None)
+                    .param(nickname)
+                    .trailing(line)
+                    .build()
+            })
+            .collect_vec()
+    }
+
+    /// Split monitored nicknames into the masks of online users and offline nicknames.
+    fn monitor_status(query: &ServerQuery, targets: &[String]) -> (Vec<String>, Vec<String>) {
+        let mut online = vec![];
+        let mut offline = vec![];
+        for target in targets {
+            match query.user_find_by_nickname(target) {
+                Some(user) if user.registered => online.push(format!(
+                    "{}!{}@{}",
+                    user.nickname.clone().unwrap(),
+                    user.username.clone().unwrap(),
+                    user.visible_host()
+                )),
+                _ => offline.push(target.clone()),
+            }
+        }
+        (online, offline)
+    }
+
+    /// Inform users monitoring `nickname` that it came online (with its
+    /// `nick!user@host` mask) or went offline.
+    fn inform_monitors(query: &mut ServerQuery, nickname: &str, mask: Option<String>) {
+        let key = nickname.to_ascii_lowercase();
+        let watchers = query
+            .server()
+            .users
+            .iter()
+            .filter(|user| user.registered && user.monitor.contains(&key))
+            .map(|user| user.client_id)
+            .collect_vec();
+        Action::MonitorInform {
+            nickname: nickname.to_string(),
+            mask,
         }
+        .dispatch_multi_by_client_id(query, &watchers[..]);
+    }
+
+    /// Inform channel peers that negotiated account-notify about the account
+    /// of a registered user, `*` if they logged out.
+    fn inform_account_notify(query: &mut ServerQuery, client_id: u64, account: &str) {
+        guard!(let Some(user) = query.user_find_by_client_id(client_id).filter(|user| user.registered) else {
+            return;
+        });
+        let account_notify = Action::AccountNotify {
+            nickname: user.nickname.clone().unwrap(),
+            username: user.username.clone().unwrap(),
+            host: user.visible_host().to_string(),
+            account: account.to_string(),
+            tags: query.event_tags(),
+        };
+        let peers = query
+            .channel_peers(client_id)
+            .into_iter()
+            .filter(|peer| {
+                query
+                    .user_find_by_client_id(*peer)
+                    .is_some_and(|user| user.has_capability(Capability::AccountNotify))
+            })
+            .collect_vec();
+        account_notify.dispatch_multi_by_client_id(query, &peers[..]);
+    }
+
+    /// Build ERR_NOPRIVILEGES for a missing operator privilege.
+    fn no_privileges_message(
+        query: &ServerQuery,
+        server_host: &str,
+        privilege: Privilege,
+    ) -> Message {
+        let text = if query.user().has_mode(UserMode::Operator) {
+            format!(
+                "Permission Denied - You need the {} privilege",
+                privilege.name()
+            )
+        } else {
+            String::from("Permission Denied - You're not an IRC operator")
+        };
+        MessageBuilder::new(ERR_NOPRIVILEGES)
+            .prefix(server_host, None, None)
+            .param(&query.user().nickname_or_star())
+            .trailing(&text)
+            .build()
     }
 }
@@ -1,4 +1,7 @@
-use crate::models::ChannelRef;
+use crate::models::{
+    BanKind, Capability, ChannelRef, HistoryRef, HistorySelector, ListFilter, MessageKind,
+    MessageTags, ReplyKind,
+};
 use crate::numerics::*;
 use guard::guard;
 use irc_rust::Message;
@@ -9,7 +12,27 @@ use super::{action::Action, server_query::ServerQuery};
 pub struct ActionParser;
 
 impl ActionParser {
-    pub fn parse(message: Message, query: &mut ServerQuery) -> Option<Action> {
+    /// Parse a message, `tags` are all tags sent along with it.
+    /// Only client-only tags are relayed.
+    pub fn parse(message: Message, tags: MessageTags, query: &mut ServerQuery) -> Option<Action> {
+        let client_tags = tags.client_only();
+
+        // Only a handful of commands are valid before registration has completed
+        let pre_registration_commands = [
+            "CAP",
+            "AUTHENTICATE",
+            "NICK",
+            "USER",
+            "PING",
+            "PONG",
+            "QUIT",
+        ];
+        if !query.user().registered && !pre_registration_commands.contains(&message.command()) {
+            return Some(Action::Error {
+                code: ERR_NOTREGISTERED,
+            });
+        }
+
         match message.command() {
             "PING" => {
                 // Validate params
@@ -24,6 +47,9 @@ impl ActionParser {
                 return Some(Action::Pong { challenge: None });
             }
 
+            // Liveness is tracked for every received line, see `Server::check_keepalive`
+            "PONG" => None,
+
             // NICK <nickname>
             "NICK" => {
                 // Validate params
@@ -33,6 +59,11 @@ impl ActionParser {
                 guard!(let Some(nickname) = params.iter().nth(0) else {
                     return Some(Action::Error { code: ERR_NONICKNAMEGIVEN })
                 });
+                if nickname.len() > query.server_config().feat_nicklen as usize {
+                    return Some(Action::Error {
+                        code: ERR_ERRONEUSNICKNAME,
+                    });
+                }
 
                 // Check if user already has a nickname
                 if let Some(old_nickname) = &query.user().nickname {
@@ -106,16 +137,347 @@ impl ActionParser {
 
                 // Dispatch registration
                 Some(Action::SetUserAndRealName {
-                    username: format!("~{}", username),
+                    username: query
+                        .user()
+                        .ident
+                        .clone()
+                        .unwrap_or_else(|| format!("~{}", username)),
+                    realname: truncate(realname, query.server_config().feat_namelen as usize),
+                })
+            }
+
+            // CAP <subcommand> [:<capabilities>]
+            "CAP" => {
+                guard!(let Some(params) = message.params() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let mut params_iter = params.iter();
+                guard!(let Some(subcommand) = params_iter.next() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let argument = params_iter
+                    .next()
+                    .or_else(|| params.trailing())
+                    .unwrap_or_default();
+
+                match subcommand.to_uppercase().as_ref() {
+                    "LS" => Some(Action::CapLs {
+                        version: argument.parse().unwrap_or(301),
+                    }),
+                    "LIST" => Some(Action::CapList),
+                    "REQ" => Some(Action::CapReq {
+                        capabilities: argument
+                            .split_whitespace()
+                            .map(ToString::to_string)
+                            .collect_vec(),
+                    }),
+                    "END" => Some(Action::CapEnd),
+                    _ => Some(Action::CapInvalid {
+                        subcommand: subcommand.to_string(),
+                    }),
+                }
+            }
+
+            // AUTHENTICATE <mechanism>
+            // AUTHENTICATE <base64 data>
+            "AUTHENTICATE" => {
+                guard!(let Some(data) = message.params().and_then(|params| params.iter().next()) else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                Some(Action::Authenticate {
+                    data: data.to_string(),
+                })
+            }
+
+            // SETNAME :<realname>
+            "SETNAME" => {
+                guard!(let Some(realname) = message.params().and_then(|params| params.iter().next().or_else(|| params.trailing())) else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                if realname.is_empty()
+                    || realname.len() > query.server_config().feat_namelen as usize
+                {
+                    return Some(Action::StandardReply {
+                        kind: ReplyKind::Fail,
+                        command: String::from("SETNAME"),
+                        code: String::from("INVALID_REALNAME"),
+                        context: vec![],
+                        description: String::from("Realname is not valid"),
+                    });
+                }
+                Some(Action::SetName {
                     realname: realname.to_string(),
                 })
             }
 
+            // MONITOR (+|-) <target>[,<target>]*
+            // MONITOR (C|L|S)
+            "MONITOR" => {
+                guard!(let Some(params) = message.params() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let mut params_iter = params.iter();
+                guard!(let Some(subcommand) = params_iter.next() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let targets = params_iter
+                    .next()
+                    .or_else(|| params.trailing())
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter(|target| !target.is_empty())
+                    .map(ToString::to_string)
+                    .collect_vec();
+
+                match subcommand.to_uppercase().as_ref() {
+                    "+" | "-" if targets.is_empty() => Some(Action::Error {
+                        code: ERR_NEEDMOREPARAMS,
+                    }),
+                    "+" => Some(Action::MonitorAdd { targets }),
+                    "-" => Some(Action::MonitorRemove { targets }),
+                    "C" => Some(Action::MonitorClear),
+                    "L" => Some(Action::MonitorList),
+                    "S" => Some(Action::MonitorStatus),
+                    _ => None,
+                }
+            }
+
+            // ISON <nickname>{ <nickname>}
+            "ISON" => {
+                let nicknames = message
+                    .params()
+                    .map(|params| {
+                        params
+                            .iter()
+                            .chain(params.trailing())
+                            .flat_map(str::split_whitespace)
+                            .map(ToString::to_string)
+                            .collect_vec()
+                    })
+                    .unwrap_or_default();
+                if nicknames.is_empty() {
+                    return Some(Action::Error {
+                        code: ERR_NEEDMOREPARAMS,
+                    });
+                }
+                Some(Action::Ison { nicknames })
+            }
+
+            // USERHOST <nickname>{ <nickname>}
+            "USERHOST" => {
+                let nicknames = message
+                    .params()
+                    .map(|params| {
+                        params
+                            .iter()
+                            .chain(params.trailing())
+                            .flat_map(str::split_whitespace)
+                            .take(5)
+                            .map(ToString::to_string)
+                            .collect_vec()
+                    })
+                    .unwrap_or_default();
+                if nicknames.is_empty() {
+                    return Some(Action::Error {
+                        code: ERR_NEEDMOREPARAMS,
+                    });
+                }
+                Some(Action::Userhost { nicknames })
+            }
+
+            // AWAY [<message>]
+            "AWAY" => {
+                let away_message = message
+                    .params()
+                    .and_then(|params| params.iter().next().or_else(|| params.trailing()))
+                    .filter(|away_message| !away_message.is_empty())
+                    .map(|away_message| {
+                        truncate(away_message, query.server_config().feat_awaylen as usize)
+                    });
+                Some(Action::Away {
+                    message: away_message,
+                })
+            }
+
+            // WHO <mask>
+            "WHO" => {
+                guard!(let Some(mask) = message.params().and_then(|params| params.iter().next()) else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                Some(Action::Who {
+                    mask: mask.to_string(),
+                })
+            }
+
+            // WHOIS [<server>] <nickname>
+            "WHOIS" => {
+                guard!(let Some(nickname) = message.params().and_then(|params| params.iter().last()) else {
+                    return Some(Action::Error { code: ERR_NONICKNAMEGIVEN })
+                });
+                Some(Action::Whois {
+                    nickname: nickname.to_string(),
+                })
+            }
+
             "MOTD" => {
                 // Dispatch MOTD reply
                 Some(Action::Motd)
             }
 
+            // OPER <name> <password>
+            "OPER" => {
+                guard!(let Some(params) = message.params() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let mut params_iter = params.iter();
+                guard!(let Some(name) = params_iter.next() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                guard!(let Some(password) = params_iter.next().or_else(|| params.trailing()) else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                Some(Action::Oper {
+                    name: name.to_string(),
+                    password: password.to_string(),
+                })
+            }
+
+            "DIE" => Some(Action::Die),
+            "REHASH" => Some(Action::Rehash),
+
+            // KLINE [<duration>] <user@host> [<reason>]
+            // DLINE [<duration>] <ip/cidr> [<reason>]
+            // GLINE [<duration>] <user@host> [<reason>]
+            "KLINE" | "DLINE" | "GLINE" => {
+                let kind = match message.command() {
+                    "KLINE" => BanKind::Kline,
+                    "DLINE" => BanKind::Dline,
+                    _ => BanKind::Gline,
+                };
+                let mut args = message
+                    .params()
+                    .map(|params| params.iter().chain(params.trailing()).collect_vec())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .peekable();
+                let duration = match args.peek().map(|arg| (arg, parse_duration(arg))) {
+                    Some((_, Some(duration))) => {
+                        args.next();
+                        Some(duration)
+                    }
+                    Some((arg, None)) if is_duration(arg) => {
+                        return Some(Action::StandardReply {
+                            kind: ReplyKind::Fail,
+                            command: message.command().to_string(),
+                            code: String::from("INVALID_PARAMS"),
+                            context: vec![arg.to_string()],
+                            description: String::from("Invalid ban duration"),
+                        })
+                    }
+                    _ => None,
+                };
+                guard!(let Some(mask) = args.next() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let reason = args.join(" ");
+                Some(Action::AddBan {
+                    kind,
+                    mask: mask.to_string(),
+                    duration,
+                    reason: if reason.is_empty() {
+                        String::from("No reason")
+                    } else {
+                        reason
+                    },
+                })
+            }
+
+            // UNKLINE <user@host>
+            // UNDLINE <ip/cidr>
+            // UNGLINE <user@host>
+            "UNKLINE" | "UNDLINE" | "UNGLINE" => {
+                let kind = match message.command() {
+                    "UNKLINE" => BanKind::Kline,
+                    "UNDLINE" => BanKind::Dline,
+                    _ => BanKind::Gline,
+                };
+                guard!(let Some(mask) = message.params().and_then(|params| params.iter().next().or_else(|| params.trailing())) else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                Some(Action::RemoveBan {
+                    kind,
+                    mask: mask.to_string(),
+                })
+            }
+
+            // STATS <query>
+            "STATS" => {
+                guard!(let Some(letter) = message.params().and_then(|params| params.iter().next().or_else(|| params.trailing())).and_then(|query| query.chars().next()) else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                Some(Action::Stats { letter })
+            }
+
+            // KILL <nickname> <reason>
+            "KILL" => {
+                guard!(let Some(params) = message.params() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let mut params_iter = params.iter();
+                guard!(let Some(nickname) = params_iter.next() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                guard!(let Some(reason) = params_iter.next().or_else(|| params.trailing()) else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                Some(Action::Kill {
+                    nickname: nickname.to_string(),
+                    reason: reason.to_string(),
+                })
+            }
+
+            // CHGHOST <nickname> <host>
+            "CHGHOST" => {
+                guard!(let Some(params) = message.params() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let mut params_iter = params.iter();
+                guard!(let (Some(nickname), Some(host)) = (params_iter.next(), params_iter.next().or_else(|| params.trailing())) else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                Some(Action::ChgHost {
+                    nickname: nickname.to_string(),
+                    host: host.to_string(),
+                })
+            }
+
+            // WALLOPS <text>
+            // GLOBOPS <text>
+            "WALLOPS" | "GLOBOPS" => {
+                guard!(let Some(text) = message.params().and_then(|params| params.iter().next().or_else(|| params.trailing())) else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                if message.command() == "WALLOPS" {
+                    Some(Action::Wallops {
+                        message: text.to_string(),
+                    })
+                } else {
+                    Some(Action::Globops {
+                        message: text.to_string(),
+                    })
+                }
+            }
+
+            "LUSERS" => Some(Action::Lusers),
+
+            "VERSION" => Some(Action::Version),
+
+            "TIME" => Some(Action::Time),
+
+            "ADMIN" => Some(Action::Admin),
+
+            "INFO" => Some(Action::Info),
+
             "JOIN" => {
                 guard!(let Some(params) = message.params() else {
                     return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
@@ -135,7 +497,102 @@ impl ActionParser {
                 })
             }
 
-            "PRIVMSG" => {
+            // LIST [<channel>{,<channel>}|<elistcond>{,<elistcond>}] [<server>]
+            "LIST" => {
+                let filters = message
+                    .params()
+                    .and_then(|params| params.iter().next())
+                    .map(|filters| filters.split(',').flat_map(ListFilter::parse).collect_vec())
+                    .unwrap_or_default();
+                Some(Action::List { filters })
+            }
+
+            // MODE <target> [<modestring>]
+            "MODE" => {
+                guard!(let Some(params) = message.params() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let mut params_iter = params.iter();
+                guard!(let Some(target) = params_iter.next() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let changes = parse_mode_changes(params_iter.next().unwrap_or_default());
+
+                if target.starts_with(['#', '&']) {
+                    // Parameters of prefix modes, e.g. the nickname for `+o`
+                    let params = params_iter
+                        .chain(params.trailing())
+                        .map(ToString::to_string)
+                        .collect_vec();
+                    Some(Action::ChannelMode {
+                        channel: target.to_string(),
+                        changes,
+                        params,
+                    })
+                } else {
+                    Some(Action::UserMode {
+                        nickname: target.to_string(),
+                        changes,
+                    })
+                }
+            }
+
+            // NAMES [<channel>{,<channel>}]
+            "NAMES" => {
+                let channels = message
+                    .params()
+                    .and_then(|params| params.iter().next())
+                    .map(|channels| channels.split(',').map(ToString::to_string).collect_vec())
+                    .unwrap_or_default();
+                Some(Action::Names { channels })
+            }
+
+            // KICK <channel> <user>{,<user>} [<comment>]
+            "KICK" => {
+                guard!(let Some(params) = message.params() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let mut params_iter = params.iter();
+                guard!(let Some(channel) = params_iter.next() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                guard!(let Some(users) = params_iter.next() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let comment = params_iter
+                    .next()
+                    .or_else(|| params.trailing())
+                    .map(|comment| truncate(comment, query.server_config().feat_kicklen as usize));
+                Some(Action::Kick {
+                    channel: channel.to_string(),
+                    users: users.split(',').map(ToString::to_string).collect_vec(),
+                    comment,
+                })
+            }
+
+            // TOPIC <channel> [<topic>]
+            "TOPIC" => {
+                guard!(let Some(params) = message.params() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let mut params_iter = params.iter();
+                guard!(let Some(channel) = params_iter.next() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                let topic = params_iter
+                    .next()
+                    .or_else(|| params.trailing())
+                    .map(|topic| truncate(topic, query.server_config().feat_topiclen as usize));
+                Some(Action::Topic {
+                    channel: channel.to_string(),
+                    topic,
+                })
+            }
+
+            // PRIVMSG/NOTICE <targets> <text>
+            // TAGMSG <targets>
+            "PRIVMSG" | "NOTICE" | "TAGMSG" => {
+                let kind = MessageKind::from_command(message.command())?;
                 guard!(let Some(params) = message.params() else {
                     return Some(Action::Error { code: ERR_NEEDMOREPARAMS });
                 });
@@ -143,6 +600,11 @@ impl ActionParser {
                 guard!(let Some(targets) = params_iter.next().map(|s| s.split(",").collect_vec()) else {
                     return Some(Action::Error { code: ERR_NEEDMOREPARAMS });
                 });
+                if targets.len() > query.server_config().feat_maxtargets as usize {
+                    return Some(Action::Error {
+                        code: ERR_TOOMANYTARGETS,
+                    });
+                }
                 let message = {
                     if let Some(message) = params_iter.next() {
                         Some(message)
@@ -152,9 +614,31 @@ impl ActionParser {
                         None
                     }
                 };
-                guard!(let Some(message) = message else {
-                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS });
-                });
+                let message = match (kind, message) {
+                    (MessageKind::Tagmsg, _) => String::new(),
+                    (_, Some(message)) => message.to_string(),
+                    (_, None) => {
+                        return Some(Action::Error {
+                            code: ERR_NEEDMOREPARAMS,
+                        })
+                    }
+                };
+
+                // Messages of a multiline batch are collected until the batch ends
+                if let Some(reference) = tags.get("batch") {
+                    return Some(Action::MultilineLine {
+                        reference: reference.to_string(),
+                        kind,
+                        target: targets.join(","),
+                        text: message,
+                        concat: tags.get("draft/multiline-concat").is_some(),
+                    });
+                }
+
+                // TAGMSG only exists to carry tags
+                if kind == MessageKind::Tagmsg && client_tags.is_empty() {
+                    return None;
+                }
 
                 let channel_targets = targets
                     .iter()
@@ -174,12 +658,75 @@ impl ActionParser {
                     .collect_vec();
 
                 Some(Action::PrivateMessage {
-                    message: message.to_string(),
+                    kind,
+                    message,
+                    lines: vec![],
                     channels: channel_targets,
                     users: user_targets,
+                    tags: client_tags,
                 })
             }
 
+            // BATCH +<reference> draft/multiline <target>
+            // BATCH -<reference>
+            "BATCH" if query.user().has_capability(Capability::Multiline) => {
+                let params = message
+                    .params()
+                    .map(|params| params.iter().chain(params.trailing()).collect_vec())
+                    .unwrap_or_default();
+                guard!(let Some(reference) = params.first() else {
+                    return Some(Action::Error { code: ERR_NEEDMOREPARAMS })
+                });
+                if let Some(reference) = reference.strip_prefix('-') {
+                    return Some(Action::MultilineEnd {
+                        reference: reference.to_string(),
+                    });
+                }
+                guard!(let Some(reference) = reference.strip_prefix('+').filter(|reference| !reference.is_empty()) else {
+                    return Some(Action::StandardReply {
+                        kind: ReplyKind::Fail,
+                        command: String::from("BATCH"),
+                        code: String::from("INVALID_PARAMS"),
+                        context: vec![reference.to_string()],
+                        description: String::from("Invalid batch reference"),
+                    })
+                });
+                match (params.get(1), params.get(2)) {
+                    (Some(&"draft/multiline"), Some(target)) => Some(Action::MultilineStart {
+                        reference: reference.to_string(),
+                        target: target.to_string(),
+                        tags: client_tags,
+                    }),
+                    (Some(batch_type), Some(_)) => Some(Action::StandardReply {
+                        kind: ReplyKind::Fail,
+                        command: String::from("BATCH"),
+                        code: String::from("INVALID_PARAMS"),
+                        context: vec![batch_type.to_string()],
+                        description: String::from("Unsupported batch type"),
+                    }),
+                    _ => Some(Action::Error {
+                        code: ERR_NEEDMOREPARAMS,
+                    }),
+                }
+            }
+
+            // CHATHISTORY <subcommand> <target> <reference>... <limit>
+            // CHATHISTORY TARGETS <timestamp> <timestamp> <limit>
+            "CHATHISTORY" => {
+                let params = message
+                    .params()
+                    .map(|params| {
+                        params
+                            .iter()
+                            .chain(params.trailing())
+                            .map(ToString::to_string)
+                            .collect_vec()
+                    })
+                    .unwrap_or_default();
+                let max_results = query.server_config().history.max_results as usize;
+                Some(parse_chathistory(&params, max_results))
+            }
+
             "QUIT" => {
                 let reason = {
                     if let Some(params) = message.params() {
@@ -202,3 +749,196 @@ impl ActionParser {
         }
     }
 }
+
+/// Truncate a string to at most `max_len` bytes without splitting a character.
+fn truncate(text: &str, max_len: usize) -> String {
+    let mut end = text.len().min(max_len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
+
+/// Parse the parameters of CHATHISTORY, invalid ones result in a FAIL reply.
+fn parse_chathistory(params: &[String], max_results: usize) -> Action {
+    let fail = |code: &str, context: &[&str], description: &str| Action::StandardReply {
+        kind: ReplyKind::Fail,
+        command: String::from("CHATHISTORY"),
+        code: code.to_string(),
+        context: context.iter().map(ToString::to_string).collect_vec(),
+        description: description.to_string(),
+    };
+
+    let subcommand = params
+        .first()
+        .map(|subcommand| subcommand.to_ascii_uppercase())
+        .unwrap_or_default();
+    let param_count = match subcommand.as_str() {
+        "" => return fail("NEED_MORE_PARAMS", &[], "Missing parameters"),
+        "TARGETS" | "LATEST" | "BEFORE" | "AFTER" | "AROUND" => 4,
+        "BETWEEN" => 5,
+        _ => return fail("INVALID_PARAMS", &[&subcommand], "Unknown subcommand"),
+    };
+    if params.len() < param_count {
+        return fail("NEED_MORE_PARAMS", &[&subcommand], "Missing parameters");
+    }
+    let limit = &params[param_count - 1];
+    let limit = match limit.parse::<usize>() {
+        Ok(limit) if limit > 0 => limit.min(max_results),
+        _ => return fail("INVALID_PARAMS", &[&subcommand, limit], "Invalid limit"),
+    };
+
+    // All parameters between the target and the limit are references
+    let references = params[2..param_count - 1]
+        .iter()
+        .map(|reference| HistoryRef::parse(reference).ok_or(reference))
+        .collect::<Result<Vec<_>, _>>();
+    let references = match (subcommand.as_str(), references) {
+        ("LATEST", _) if params[2] == "*" => vec![],
+        (_, Ok(references)) => references,
+        (_, Err(reference)) => {
+            return fail(
+                "INVALID_PARAMS",
+                &[&subcommand, reference],
+                "Invalid message reference",
+            )
+        }
+    };
+
+    let target = params[1].clone();
+    let selector = match (subcommand.as_str(), &references[..]) {
+        ("TARGETS", _) => {
+            return match (HistoryRef::parse(&params[1]), &references[..]) {
+                (Some(HistoryRef::Timestamp(start)), [HistoryRef::Timestamp(end)]) => {
+                    Action::ChatHistoryTargets {
+                        between: (start, *end),
+                        limit,
+                    }
+                }
+                _ => fail(
+                    "INVALID_PARAMS",
+                    &[&subcommand],
+                    "TARGETS requires two timestamps",
+                ),
+            };
+        }
+        ("LATEST", []) => HistorySelector::Latest(None),
+        ("LATEST", [reference]) => HistorySelector::Latest(Some(reference.clone())),
+        ("BEFORE", [reference]) => HistorySelector::Before(reference.clone()),
+        ("AFTER", [reference]) => HistorySelector::After(reference.clone()),
+        ("AROUND", [reference]) => HistorySelector::Around(reference.clone()),
+        (_, [start, end]) => HistorySelector::Between(start.clone(), end.clone()),
+        _ => return fail("INVALID_PARAMS", &[&subcommand], "Invalid parameters"),
+    };
+    Action::ChatHistory {
+        target,
+        selector,
+        limit,
+    }
+}
+
+/// Parse a ban duration in seconds. Plain numbers are minutes, otherwise
+/// units can be combined, e.g. `1d12h` or `30m`. Durations that aren't
+/// positive or don't fit into an `i64` are rejected.
+fn parse_duration(text: &str) -> Option<i64> {
+    if text.chars().all(|c| c.is_ascii_digit()) {
+        return text
+            .parse::<i64>()
+            .ok()?
+            .checked_mul(60)
+            .filter(|seconds| *seconds > 0);
+    }
+
+    let mut seconds = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        seconds = number
+            .parse::<i64>()
+            .ok()?
+            .checked_mul(unit)?
+            .checked_add(seconds)?;
+        number.clear();
+    }
+    Some(seconds).filter(|seconds| number.is_empty() && *seconds > 0)
+}
+
+/// Whether a ban argument is meant as a duration, i.e. consists of digits
+/// and units, optionally signed.
+fn is_duration(text: &str) -> bool {
+    let text = text.strip_prefix(['-', '+']).unwrap_or(text);
+    text.starts_with(|c: char| c.is_ascii_digit())
+        && text
+            .chars()
+            .all(|c| c.is_ascii_digit() || "smhdw".contains(c.to_ascii_lowercase()))
+}
+
+/// Parse a mode string like `+s-p` into (adding, letter) pairs.
+fn parse_mode_changes(modestring: &str) -> Vec<(bool, char)> {
+    let mut adding = true;
+    modestring
+        .chars()
+        .filter_map(|c| match c {
+            '+' => {
+                adding = true;
+                None
+            }
+            '-' => {
+                adding = false;
+                None
+            }
+            letter => Some((adding, letter)),
+        })
+        .collect_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30"), Some(30 * 60));
+        assert_eq!(parse_duration("45s"), Some(45));
+        assert_eq!(parse_duration("1d12h"), Some(36 * 60 * 60));
+        assert_eq!(parse_duration("2W"), Some(14 * 24 * 60 * 60));
+        assert_eq!(parse_duration("1h30"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("*@host"), None);
+        assert_eq!(parse_duration("192.0.2.1"), None);
+    }
+
+    #[test]
+    fn duration_overflow() {
+        assert_eq!(parse_duration(&i64::MAX.to_string()), None);
+        assert_eq!(parse_duration("99999999999999999999"), None);
+        assert_eq!(parse_duration("9223372036854775807s"), Some(i64::MAX));
+        assert_eq!(parse_duration("9223372036854775807m"), None);
+        assert_eq!(parse_duration("9223372036854775807s1s"), None);
+        assert!(is_duration("99999999999999999999"));
+        assert!(is_duration("1d12h"));
+        assert!(!is_duration("192.0.2.1"));
+        assert!(!is_duration("*@host"));
+    }
+
+    #[test]
+    fn durations_must_be_positive() {
+        for text in ["0", "0s", "0d0h", "-5", "-5m", "+5"] {
+            assert_eq!(parse_duration(text), None, "{}", text);
+            assert!(is_duration(text), "{}", text);
+        }
+        assert!(!is_duration("-"));
+        assert!(!is_duration("-*@host"));
+    }
+}
@@ -0,0 +1,67 @@
+use itertools::Itertools;
+use std::net::IpAddr;
+
+use crate::{config::ConnectClassConfig, mask};
+
+/// Assigns connection classes to incoming connections and enforces
+/// their limits. Shared between the listener and the server, which
+/// updates the classes on REHASH.
+#[derive(Debug)]
+pub struct Admission {
+    classes: Vec<ConnectClassConfig>,
+    /// Address and class name of every open connection.
+    connections: Vec<(IpAddr, String)>,
+}
+
+impl Admission {
+    pub fn new(classes: Vec<ConnectClassConfig>) -> Self {
+        Self {
+            classes,
+            connections: vec![],
+        }
+    }
+
+    pub fn set_classes(&mut self, classes: Vec<ConnectClassConfig>) {
+        self.classes = classes;
+    }
+
+    /// Find the class of a new connection and count it towards its limits.
+    /// Returns the reason for rejecting the connection otherwise.
+    pub fn admit(&mut self, ip: IpAddr) -> Result<String, String> {
+        let class = self
+            .classes
+            .iter()
+            .find(|class| class.masks.iter().any(|mask| mask::matches_ip(mask, ip)))
+            .ok_or_else(|| String::from("You are not authorized to use this server"))?;
+
+        let class_connections = self
+            .connections
+            .iter()
+            .filter(|(_, name)| *name == class.name)
+            .collect_vec();
+        if class_connections.len() >= class.max_clients as usize {
+            return Err(String::from("Server is full, try again later"));
+        }
+        let ip_connections = class_connections
+            .iter()
+            .filter(|(other_ip, _)| *other_ip == ip)
+            .count();
+        if ip_connections >= class.max_per_ip as usize {
+            return Err(String::from("Too many connections from your IP"));
+        }
+
+        self.connections.push((ip, class.name.clone()));
+        Ok(class.name.clone())
+    }
+
+    /// Stop counting a closed connection.
+    pub fn release(&mut self, ip: IpAddr, class: &str) {
+        if let Some(index) = self
+            .connections
+            .iter()
+            .position(|(other_ip, name)| *other_ip == ip && name == class)
+        {
+            self.connections.swap_remove(index);
+        }
+    }
+}
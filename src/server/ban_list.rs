@@ -0,0 +1,90 @@
+use anyhow::Result;
+use itertools::Itertools;
+use std::{fs, net::IpAddr, path::Path};
+
+use crate::models::{Ban, BanKind};
+
+/// Server bans, persisted as JSON in `ban_file`.
+#[derive(Debug)]
+pub struct BanList {
+    path: String,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    /// Load the ban database, starting with an empty one if the file doesn't exist yet.
+    pub fn load(path: &str) -> Result<Self> {
+        let mut bans: Vec<Ban> = if Path::new(path).exists() {
+            serde_json::from_str(&fs::read_to_string(path)?)?
+        } else {
+            vec![]
+        };
+        bans.retain(|ban| !ban.is_expired());
+        Ok(Self {
+            path: path.to_string(),
+            bans,
+        })
+    }
+
+    /// Empty ban list that is saved to `path`, used if loading fails.
+    pub fn empty(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            bans: vec![],
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::write(&self.path, serde_json::to_string_pretty(&self.bans)?)?;
+        Ok(())
+    }
+
+    /// Add a ban, replacing an existing ban of the same kind and mask.
+    pub fn add(&mut self, ban: Ban) -> Result<()> {
+        self.bans.retain(|existing| {
+            let replaced =
+                existing.kind == ban.kind && existing.mask.eq_ignore_ascii_case(&ban.mask);
+            !existing.is_expired() && !replaced
+        });
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Remove a ban, returns whether a matching ban existed.
+    pub fn remove(&mut self, kind: BanKind, mask: &str) -> Result<bool> {
+        let count = self.bans.len();
+        self.bans
+            .retain(|ban| !(ban.kind == kind && ban.mask.eq_ignore_ascii_case(mask)));
+        if self.bans.len() == count {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Active bans of the given kind.
+    pub fn list(&self, kind: BanKind) -> Vec<&Ban> {
+        self.bans
+            .iter()
+            .filter(|ban| ban.kind == kind && !ban.is_expired())
+            .collect_vec()
+    }
+
+    /// Find a D-line matching the IP address.
+    pub fn find_dline(&self, ip: IpAddr) -> Option<&Ban> {
+        self.bans
+            .iter()
+            .find(|ban| ban.kind == BanKind::Dline && ban.matches(None, &[], ip))
+    }
+
+    /// Find any ban matching the connection.
+    pub fn find(&self, username: &str, hosts: &[&str], ip: IpAddr) -> Option<&Ban> {
+        self.bans
+            .iter()
+            .find(|ban| ban.matches(Some(username), hosts, ip))
+    }
+}
@@ -0,0 +1,164 @@
+use itertools::Itertools;
+
+use crate::{
+    config::ServerConfig,
+    models::{ChannelMode, UserMode},
+};
+
+/// Maximum number of tokens sent in a single RPL_ISUPPORT line.
+const TOKENS_PER_LINE: usize = 13;
+
+/// Registry of feature tokens advertised via RPL_ISUPPORT.
+#[derive(Debug, PartialEq)]
+pub struct ISupport {
+    tokens: Vec<(String, Option<String>)>,
+}
+
+impl ISupport {
+    /// Generate the tokens from the configuration and implemented features.
+    pub fn new(config: &ServerConfig) -> Self {
+        let mut isupport = Self { tokens: vec![] };
+        let prefixes = ChannelMode::prefix_letters();
+
+        isupport.value("AWAYLEN", config.feat_awaylen);
+        isupport.value("CASEMAPPING", config.feat_casemap.to_string());
+        isupport.value("CHANLIMIT", format!("#&:{}", config.feat_chanlimit));
+        isupport.value("CHANMODES", format!(",,,{}", ChannelMode::flag_letters()));
+        isupport.value("CHANNELLEN", config.feat_channellen);
+        isupport.value("CHANTYPES", "#&");
+        isupport.value("CHATHISTORY", config.history.max_results);
+        isupport.value("ELIST", "CMNTU");
+        isupport.value("KICKLEN", config.feat_kicklen);
+        isupport.value("MAXTARGETS", config.feat_maxtargets);
+        isupport.value("MODES", config.feat_modes);
+        isupport.value("MONITOR", config.feat_monitor);
+        isupport.value("NAMELEN", config.feat_namelen);
+        isupport.value("NETWORK", &config.network);
+        isupport.value("NICKLEN", config.feat_nicklen);
+        isupport.value(
+            "PREFIX",
+            format!(
+                "({}){}",
+                prefixes.iter().map(|(letter, _)| letter).join(""),
+                prefixes.iter().map(|(_, symbol)| symbol).join("")
+            ),
+        );
+        isupport.value(
+            "TARGMAX",
+            format!("JOIN:,LIST:,PRIVMSG:{},WHOIS:1", config.feat_maxtargets),
+        );
+        isupport.value("TOPICLEN", config.feat_topiclen);
+        isupport
+    }
+
+    fn value(&mut self, name: &str, value: impl ToString) {
+        self.tokens
+            .push((name.to_string(), Some(value.to_string())));
+    }
+
+    /// All tokens, formatted as `NAME` or `NAME=value`.
+    pub fn tokens(&self) -> Vec<String> {
+        self.tokens
+            .iter()
+            .map(|(name, value)| match value {
+                Some(value) if !value.is_empty() => format!("{}={}", name, value),
+                _ => name.clone(),
+            })
+            .collect_vec()
+    }
+
+    /// Tokens that changed compared to a previous registry.
+    /// Removed tokens are negated as `-NAME`.
+    pub fn changes_since(&self, previous: &ISupport) -> Vec<String> {
+        let changed = self
+            .tokens
+            .iter()
+            .zip(self.tokens())
+            .filter(|(token, _)| !previous.tokens.contains(token))
+            .map(|(_, formatted)| formatted);
+        let removed = previous
+            .tokens
+            .iter()
+            .filter(|(name, _)| self.tokens.iter().all(|(other, _)| other != name))
+            .map(|(name, _)| format!("-{}", name));
+        changed.chain(removed).collect_vec()
+    }
+
+    /// Split tokens into chunks that fit into a single RPL_ISUPPORT line each.
+    pub fn split_lines(tokens: &[String]) -> Vec<Vec<String>> {
+        tokens
+            .chunks(TOKENS_PER_LINE)
+            .map(|chunk| chunk.to_vec())
+            .collect_vec()
+    }
+
+    /// User modes for RPL_MYINFO.
+    pub fn user_modes() -> String {
+        UserMode::ALL
+            .iter()
+            .map(UserMode::letter)
+            .sorted()
+            .collect()
+    }
+
+    /// Channel modes for RPL_MYINFO, followed by the modes taking a parameter.
+    pub fn channel_modes() -> (String, String) {
+        let prefix_letters = ChannelMode::prefix_letters()
+            .into_iter()
+            .map(|(letter, _)| letter)
+            .collect::<String>();
+        let all_letters = ChannelMode::flag_letters()
+            .chars()
+            .chain(prefix_letters.chars())
+            .sorted()
+            .collect();
+        (all_letters, prefix_letters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_lines() {
+        let tokens = (0..30).map(|i| format!("T{}", i)).collect_vec();
+        let lines = ISupport::split_lines(&tokens);
+        assert_eq!(
+            lines.iter().map(Vec::len).collect_vec(),
+            vec![TOKENS_PER_LINE, TOKENS_PER_LINE, 4]
+        );
+        assert_eq!(lines.concat(), tokens);
+        assert!(ISupport::split_lines(&[]).is_empty());
+    }
+
+    #[test]
+    fn tokens() {
+        let isupport = ISupport::new(&ServerConfig::default());
+        let tokens = isupport.tokens();
+        assert!(tokens.contains(&String::from("CHANTYPES=#&")));
+        assert!(tokens.contains(&String::from("KICKLEN=255")));
+        assert!(tokens.contains(&String::from("PREFIX=(o)@")));
+    }
+
+    #[test]
+    fn changes_since() {
+        let previous = ISupport {
+            tokens: vec![
+                (String::from("NICKLEN"), Some(String::from("30"))),
+                (String::from("SAFELIST"), None),
+            ],
+        };
+        let current = ISupport {
+            tokens: vec![
+                (String::from("NICKLEN"), Some(String::from("20"))),
+                (String::from("CHANTYPES"), Some(String::from("#"))),
+            ],
+        };
+        assert_eq!(
+            current.changes_since(&previous),
+            vec!["NICKLEN=20", "CHANTYPES=#", "-SAFELIST"]
+        );
+        assert!(current.changes_since(&current).is_empty());
+    }
+}
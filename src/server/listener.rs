@@ -0,0 +1,107 @@
+use anyhow::Result;
+use guard::guard;
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::Sender,
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+};
+
+use super::{admission::Admission, ban_list::BanList, lookup::LookupSettings, Server, ServerEvent};
+
+/// Accept loop for a bound address. Stopped when REHASH moves the server
+/// to a different address, without affecting already connected clients.
+pub struct Listener {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Listener {
+    pub fn spawn(
+        listener: TcpListener,
+        client_ids: Arc<AtomicU64>,
+        bans: Arc<RwLock<BanList>>,
+        admission: Arc<Mutex<Admission>>,
+        lookups: Arc<RwLock<LookupSettings>>,
+        sender: Sender<ServerEvent>,
+    ) -> Result<Self> {
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let stopped_ref = stopped.clone();
+        thread::spawn(move || {
+            let mut threads: Vec<JoinHandle<()>> = Vec::new();
+            for stream in listener.incoming() {
+                if stopped_ref.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let ip = match stream.peer_addr() {
+                            Ok(peer_addr) => peer_addr.ip(),
+                            Err(_) => continue,
+                        };
+
+                        // Reject D-lined connections before looking them up,
+                        // then enforce connection class limits
+                        let admitted = match bans.read() {
+                            Ok(bans) => match bans.find_dline(ip) {
+                                Some(ban) => Err(format!("D-lined ({})", ban.reason)),
+                                None => Ok(()),
+                            },
+                            Err(_) => Err(String::from("Server error")),
+                        }
+                        .and_then(|_| match admission.lock() {
+                            Ok(mut admission) => admission.admit(ip),
+                            Err(_) => Err(String::from("Server error")),
+                        });
+                        let class = match admitted {
+                            Ok(class) => class,
+                            Err(reason) => {
+                                let error = format!("ERROR :Closing Link: {} ({})\r\n", ip, reason);
+                                let _ = (&stream).write_all(error.as_bytes());
+                                let _ = stream.shutdown(std::net::Shutdown::Both);
+                                println!("[Server] Rejected connection from {} ({})", ip, reason);
+                                continue;
+                            }
+                        };
+
+                        guard!(let Ok(lookups) = lookups.read().map(|lookups| lookups.clone()) else { continue });
+                        let sender = sender.clone();
+                        let admission = admission.clone();
+                        let client_id = client_ids.fetch_add(1, Ordering::SeqCst) + 1;
+                        let handle = thread::spawn(move || {
+                            let result =
+                                Server::handle_stream(stream, client_id, &class, &lookups, sender);
+                            if let Err(err) = result {
+                                println!("[Client(id: {})] Error: {}", client_id, err);
+                            }
+                            if let Ok(mut admission) = admission.lock() {
+                                admission.release(ip, &class);
+                            }
+                        });
+                        threads.push(handle);
+                    }
+                    Err(err) => {
+                        println!("Unable to accept client stream: {}", err)
+                    }
+                }
+            }
+            println!("[Server] Stopped listening on {}", addr);
+        });
+
+        println!("[Server] Listening on {}", addr);
+        Ok(Self { addr, stopped })
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        // Wake up the blocking accept call
+        let _ = TcpStream::connect(self.addr);
+    }
+}
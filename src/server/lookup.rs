@@ -0,0 +1,413 @@
+use anyhow::{anyhow, Result};
+use dns_parser::{Builder, Packet, QueryClass, QueryType, RData};
+use guard::guard;
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
+    sync::{mpsc::channel, Arc},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::ServerConfig;
+
+/// Maximum length of a hostname, longer names fall back to the IP.
+const HOSTLEN: usize = 63;
+
+/// Maximum length of an ident response.
+const USERLEN: usize = 10;
+
+/// Reverse and forward DNS lookups.
+pub trait Resolver: Send + Sync {
+    fn reverse(&self, ip: IpAddr) -> Result<String>;
+    fn forward(&self, hostname: &str) -> Result<Vec<IpAddr>>;
+}
+
+/// Resolver of the operating system, which also honours `/etc/hosts`.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn reverse(&self, ip: IpAddr) -> Result<String> {
+        Ok(dns_lookup::lookup_addr(&ip)?)
+    }
+
+    fn forward(&self, hostname: &str) -> Result<Vec<IpAddr>> {
+        Ok(dns_lookup::lookup_host(hostname)?)
+    }
+}
+
+/// Record data of a DNS answer.
+enum Answer {
+    Name(String),
+    Address(IpAddr),
+}
+
+/// Queries a specific DNS server over UDP, see `dns_server`.
+pub struct DnsResolver {
+    server: SocketAddr,
+    timeout: Duration,
+}
+
+impl DnsResolver {
+    fn query(&self, name: &str, query_type: QueryType) -> Result<Vec<Answer>> {
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.subsec_nanos() as u16)
+            .unwrap_or_default();
+        let mut builder = Builder::new_query(id, true);
+        builder.add_question(name, false, query_type, QueryClass::IN);
+        let query = builder
+            .build()
+            .map_err(|_| anyhow!("DNS query for {} was truncated", name))?;
+
+        let bind_addr = if self.server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.connect(self.server)?;
+        socket.send(&query)?;
+
+        let mut buffer = [0u8; 4096];
+        loop {
+            let len = socket.recv(&mut buffer)?;
+            let packet = Packet::parse(&buffer[..len])?;
+            if packet.header.id != id {
+                continue;
+            }
+            return Ok(packet
+                .answers
+                .into_iter()
+                .filter_map(|answer| match answer.data {
+                    RData::PTR(ptr) => Some(Answer::Name(ptr.0.to_string())),
+                    RData::A(a) => Some(Answer::Address(IpAddr::V4(a.0))),
+                    RData::AAAA(aaaa) => Some(Answer::Address(IpAddr::V6(aaaa.0))),
+                    _ => None,
+                })
+                .collect());
+        }
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn reverse(&self, ip: IpAddr) -> Result<String> {
+        let name = match ip {
+            IpAddr::V4(ip) => {
+                let [a, b, c, d] = ip.octets();
+                format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+            }
+            IpAddr::V6(ip) => {
+                let nibbles = ip
+                    .octets()
+                    .iter()
+                    .rev()
+                    .map(|byte| format!("{:x}.{:x}", byte & 0xf, byte >> 4))
+                    .collect::<Vec<_>>();
+                format!("{}.ip6.arpa", nibbles.join("."))
+            }
+        };
+        self.query(&name, QueryType::PTR)?
+            .into_iter()
+            .find_map(|answer| match answer {
+                Answer::Name(hostname) => Some(hostname),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("No PTR record for {}", ip))
+    }
+
+    fn forward(&self, hostname: &str) -> Result<Vec<IpAddr>> {
+        let mut addresses = vec![];
+        for query_type in [QueryType::A, QueryType::AAAA].iter() {
+            for answer in self.query(hostname, *query_type)? {
+                if let Answer::Address(ip) = answer {
+                    addresses.push(ip);
+                }
+            }
+        }
+        Ok(addresses)
+    }
+}
+
+/// Settings for the lookups performed when a client connects,
+/// shared with the listener and updated on REHASH.
+#[derive(Clone)]
+pub struct LookupSettings {
+    server_host: String,
+    resolve_hostnames: bool,
+    ident_lookups: bool,
+    ident_port: u16,
+    timeout: Duration,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl LookupSettings {
+    pub fn new(config: &ServerConfig) -> Self {
+        let timeout = Duration::from_secs(config.lookup_timeout);
+        let resolver: Arc<dyn Resolver> = match config.dns_server() {
+            Some(server) => Arc::new(DnsResolver { server, timeout }),
+            None => Arc::new(SystemResolver),
+        };
+        Self {
+            server_host: config.host.clone(),
+            resolve_hostnames: config.resolve_hostnames,
+            ident_lookups: config.ident_lookups,
+            ident_port: config.ident_port,
+            timeout,
+            resolver,
+        }
+    }
+}
+
+/// Result of the connect-time lookups.
+#[derive(Debug, Default)]
+pub struct LookupResult {
+    pub hostname: Option<String>,
+    pub ident: Option<String>,
+}
+
+/// Look up the hostname and ident of a new connection in parallel,
+/// keeping the client informed using notices.
+pub fn perform(stream: &TcpStream, settings: &LookupSettings) -> Result<LookupResult> {
+    let peer_addr = stream.peer_addr()?;
+    let local_addr = stream.local_addr()?;
+    let notice = |text: &str| {
+        let line = format!(":{} NOTICE * :*** {}\r\n", settings.server_host, text);
+        let _ = (&*stream).write_all(line.as_bytes());
+    };
+
+    let (hostname_sender, hostname_receiver) = channel();
+    if settings.resolve_hostnames {
+        notice("Looking up your hostname...");
+        let resolver = settings.resolver.clone();
+        thread::spawn(move || {
+            let _ = hostname_sender.send(lookup_hostname(resolver.as_ref(), peer_addr.ip()));
+        });
+    }
+    let (ident_sender, ident_receiver) = channel();
+    if settings.ident_lookups {
+        notice("Checking Ident");
+        let (ident_port, timeout) = (settings.ident_port, settings.timeout);
+        thread::spawn(move || {
+            let _ = ident_sender.send(lookup_ident(peer_addr, local_addr, ident_port, timeout));
+        });
+    }
+
+    // Both lookups run in parallel, so they share a deadline
+    let deadline = Instant::now() + settings.timeout;
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+
+    let mut result = LookupResult::default();
+    if settings.resolve_hostnames {
+        result.hostname = hostname_receiver.recv_timeout(remaining()).ok().flatten();
+        match result.hostname {
+            Some(_) => notice("Found your hostname"),
+            None => notice("Couldn't look up your hostname"),
+        }
+    }
+    if settings.ident_lookups {
+        result.ident = ident_receiver.recv_timeout(remaining()).ok().flatten();
+        match result.ident {
+            Some(_) => notice("Got Ident response"),
+            None => notice("No Ident response"),
+        }
+    }
+    Ok(result)
+}
+
+/// Forward-confirmed reverse DNS: the hostname is only used if it
+/// resolves back to the IP address.
+fn lookup_hostname(resolver: &dyn Resolver, ip: IpAddr) -> Option<String> {
+    let hostname = resolver.reverse(ip).ok()?;
+    let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+    let is_valid = hostname.len() <= HOSTLEN
+        && hostname.contains('.')
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+    if !is_valid {
+        return None;
+    }
+
+    let addresses = resolver.forward(&hostname).ok()?;
+    if addresses.contains(&ip) {
+        Some(hostname)
+    } else {
+        None
+    }
+}
+
+/// Query the client's identd (RFC 1413).
+fn lookup_ident(
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    ident_port: u16,
+    timeout: Duration,
+) -> Option<String> {
+    let stream =
+        TcpStream::connect_timeout(&SocketAddr::new(peer_addr.ip(), ident_port), timeout).ok()?;
+    stream.set_read_timeout(Some(timeout)).ok()?;
+    stream.set_write_timeout(Some(timeout)).ok()?;
+    let query = format!("{}, {}\r\n", peer_addr.port(), local_addr.port());
+    (&stream).write_all(query.as_bytes()).ok()?;
+
+    // <port>, <port> : USERID : <os> : <username>
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response).ok()?;
+    let mut fields = response.splitn(4, ':').map(str::trim);
+    let ports = fields.next()?.replace(' ', "");
+    guard!(let (Some(kind), Some(_), Some(username)) = (fields.next(), fields.next(), fields.next()) else {
+        return None;
+    });
+    if kind != "USERID" {
+        return None;
+    }
+    if ports != format!("{},{}", peer_addr.port(), local_addr.port()) {
+        return None;
+    }
+
+    let username = username
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_' || *c == '.')
+        .take(USERLEN)
+        .collect::<String>();
+    if username.is_empty() {
+        None
+    } else {
+        Some(username)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Resolver answering from fixed records.
+    struct StubResolver {
+        reverse: Option<String>,
+        forward: Vec<IpAddr>,
+    }
+
+    impl Resolver for StubResolver {
+        fn reverse(&self, ip: IpAddr) -> Result<String> {
+            self.reverse
+                .clone()
+                .ok_or_else(|| anyhow!("No PTR record for {}", ip))
+        }
+
+        fn forward(&self, _hostname: &str) -> Result<Vec<IpAddr>> {
+            Ok(self.forward.clone())
+        }
+    }
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn forward_confirmed_hostname() {
+        let resolver = StubResolver {
+            reverse: Some(String::from("Host.Example.COM.")),
+            forward: vec![ip("192.0.2.2"), ip("192.0.2.1")],
+        };
+        assert_eq!(
+            lookup_hostname(&resolver, ip("192.0.2.1")),
+            Some(String::from("host.example.com"))
+        );
+    }
+
+    #[test]
+    fn forward_mismatch() {
+        let resolver = StubResolver {
+            reverse: Some(String::from("host.example.com")),
+            forward: vec![ip("192.0.2.2")],
+        };
+        assert_eq!(lookup_hostname(&resolver, ip("192.0.2.1")), None);
+    }
+
+    #[test]
+    fn invalid_hostnames() {
+        let lookup = |hostname: &str| {
+            let resolver = StubResolver {
+                reverse: Some(hostname.to_string()),
+                forward: vec![ip("192.0.2.1")],
+            };
+            lookup_hostname(&resolver, ip("192.0.2.1"))
+        };
+        assert_eq!(lookup("localhost"), None);
+        assert_eq!(lookup("evil host.example.com"), None);
+        assert_eq!(lookup("a@b.example.com"), None);
+        assert_eq!(lookup(&format!("{}.example.com", "a".repeat(60))), None);
+
+        let resolver = StubResolver {
+            reverse: None,
+            forward: vec![],
+        };
+        assert_eq!(lookup_hostname(&resolver, ip("192.0.2.1")), None);
+    }
+
+    /// Run a one-shot identd on a local port, replying with `response`
+    /// (or not at all) and returning the query it received.
+    fn identd(response: Option<&'static str>) -> (u16, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut query = String::new();
+            BufReader::new(&stream).read_line(&mut query).unwrap();
+            match response {
+                Some(response) => stream.write_all(response.as_bytes()).unwrap(),
+                None => thread::sleep(Duration::from_millis(500)),
+            }
+            query
+        });
+        (port, handle)
+    }
+
+    fn ident(port: u16, timeout: Duration) -> Option<String> {
+        let peer_addr = SocketAddr::new(ip("127.0.0.1"), 50000);
+        let local_addr = SocketAddr::new(ip("127.0.0.1"), 6667);
+        lookup_ident(peer_addr, local_addr, port, timeout)
+    }
+
+    #[test]
+    fn ident_reply() {
+        let (port, handle) = identd(Some("50000 , 6667 : USERID : UNIX : alice\r\n"));
+        assert_eq!(
+            ident(port, Duration::from_secs(2)),
+            Some(String::from("alice"))
+        );
+        assert_eq!(handle.join().unwrap(), "50000, 6667\r\n");
+    }
+
+    #[test]
+    fn ident_reply_is_sanitized() {
+        let (port, _) = identd(Some("50000,6667:USERID:UNIX:a!l@i:c e-very_long.name\r\n"));
+        assert_eq!(
+            ident(port, Duration::from_secs(2)),
+            Some(String::from("alice-very"))
+        );
+    }
+
+    #[test]
+    fn ident_errors() {
+        let (port, _) = identd(Some("50000, 6667 : ERROR : NO-USER\r\n"));
+        assert_eq!(ident(port, Duration::from_secs(2)), None);
+
+        let (port, _) = identd(Some("50001, 6667 : USERID : UNIX : alice\r\n"));
+        assert_eq!(ident(port, Duration::from_secs(2)), None);
+
+        let (port, _) = identd(Some("50000, 6667 : USERID : UNIX : !!!\r\n"));
+        assert_eq!(ident(port, Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn ident_timeout() {
+        let (port, _) = identd(None);
+        let started = Instant::now();
+        assert_eq!(ident(port, Duration::from_millis(100)), None);
+        assert!(started.elapsed() < Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,429 @@
+use anyhow::Result;
+use chrono::Utc;
+use guard::guard;
+use itertools::Itertools;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use crate::{
+    config::HistoryConfig,
+    models::{HistoryEntry, HistoryRef, HistorySelector},
+};
+
+/// History of channels and private conversations, kept in memory and
+/// optionally appended to `history.file` as JSON lines.
+#[derive(Debug)]
+pub struct MessageHistory {
+    path: Option<String>,
+    file: Option<File>,
+    entries: HashMap<String, VecDeque<HistoryEntry>>,
+}
+
+impl MessageHistory {
+    /// Load the history file, dropping messages outside the retention limits
+    /// and those of channels, which don't survive a restart. The file is
+    /// rewritten without them, so it doesn't grow forever.
+    pub fn load(config: &HistoryConfig) -> Result<Self> {
+        let mut history = Self::empty(config);
+        if let Some(path) = history.path.clone().filter(|path| Path::new(path).exists()) {
+            for line in fs::read_to_string(&path)?.lines() {
+                match serde_json::from_str::<HistoryEntry>(line) {
+                    Ok(entry) if HistoryEntry::is_channel_key(&entry.key) => {}
+                    Ok(entry) => history.push(entry, config),
+                    Err(err) => println!("[Server] Skipping invalid history entry: {}", err),
+                }
+            }
+        }
+        history.rewrite()?;
+        Ok(history)
+    }
+
+    /// Empty history using the configured file, used if loading fails.
+    pub fn empty(config: &HistoryConfig) -> Self {
+        Self {
+            path: config.file.clone(),
+            file: None,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Write the retained messages to the history file and keep appending to it.
+    fn rewrite(&mut self) -> Result<()> {
+        guard!(let Some(path) = &self.path else { return Ok(()) });
+        let lines = self
+            .entries
+            .values()
+            .flatten()
+            .sorted_by_key(|entry| entry.time)
+            .map(|entry| serde_json::to_string(entry).map(|line| line + "\n"))
+            .collect::<Result<String, _>>()?;
+        fs::write(path, lines)?;
+        self.file = Some(OpenOptions::new().append(true).open(path)?);
+        Ok(())
+    }
+
+    fn push(&mut self, entry: HistoryEntry, config: &HistoryConfig) {
+        let (max_messages, max_age) = Self::retention(config, &entry.key);
+        let entries = self.entries.entry(entry.key.clone()).or_default();
+        entries.push_back(entry);
+        while entries.len() > max_messages
+            || entries
+                .front()
+                .is_some_and(|entry| Self::is_expired(entry, max_age))
+        {
+            entries.pop_front();
+        }
+    }
+
+    /// Add a message, appending it to the history file.
+    pub fn add(&mut self, entry: HistoryEntry, config: &HistoryConfig) -> Result<()> {
+        if Self::retention(config, &entry.key).0 == 0 {
+            return Ok(());
+        }
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        }
+        self.push(entry, config);
+        Ok(())
+    }
+
+    /// Forget the messages of a channel that ceased to exist, so whoever
+    /// creates it again can't read them.
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        if self.entries.remove(key).is_none() {
+            return Ok(());
+        }
+        self.rewrite()
+    }
+
+    fn retention(config: &HistoryConfig, key: &str) -> (usize, u64) {
+        config.retention(Some(key).filter(|key| HistoryEntry::is_channel_key(key)))
+    }
+
+    fn is_expired(entry: &HistoryEntry, max_age: u64) -> bool {
+        max_age > 0 && entry.time < Utc::now().timestamp_millis() - max_age as i64 * 1000
+    }
+
+    /// Messages of a channel or conversation that are still retained and
+    /// accepted by `visible`, oldest first.
+    fn retained(
+        &self,
+        key: &str,
+        visible: &impl Fn(&HistoryEntry) -> bool,
+        config: &HistoryConfig,
+    ) -> Vec<&HistoryEntry> {
+        let (_, max_age) = Self::retention(config, key);
+        self.entries
+            .get(key)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|entry| !Self::is_expired(entry, max_age) && visible(entry))
+                    .collect_vec()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Select up to `limit` messages of a channel or conversation accepted
+    /// by `visible`, oldest first.
+    pub fn select(
+        &self,
+        key: &str,
+        selector: &HistorySelector,
+        limit: usize,
+        visible: impl Fn(&HistoryEntry) -> bool,
+        config: &HistoryConfig,
+    ) -> Vec<&HistoryEntry> {
+        let entries = self.retained(key, &visible, config);
+
+        // Index of the first message after the reference, and of the first
+        // message not before it. Unknown message IDs select nothing.
+        let after = |reference: &HistoryRef| match reference {
+            HistoryRef::Timestamp(time) => Some(entries.partition_point(|e| e.time <= *time)),
+            HistoryRef::MsgId(msgid) => entries
+                .iter()
+                .position(|e| e.msgid == *msgid)
+                .map(|i| i + 1),
+        };
+        let from = |reference: &HistoryRef| match reference {
+            HistoryRef::Timestamp(time) => Some(entries.partition_point(|e| e.time < *time)),
+            HistoryRef::MsgId(msgid) => entries.iter().position(|e| e.msgid == *msgid),
+        };
+        let first = |start: usize, end: usize| entries[start..end.max(start)].iter().take(limit);
+        let last = |start: usize, end: usize| {
+            let end = end.max(start);
+            entries[end.saturating_sub(limit).max(start)..end].iter()
+        };
+
+        let selected = match selector {
+            HistorySelector::Latest(None) => Some(last(0, entries.len()).collect_vec()),
+            HistorySelector::Latest(Some(reference)) => {
+                after(reference).map(|start| last(start, entries.len()).collect_vec())
+            }
+            HistorySelector::Before(reference) => {
+                from(reference).map(|end| last(0, end).collect_vec())
+            }
+            HistorySelector::After(reference) => {
+                after(reference).map(|start| first(start, entries.len()).collect_vec())
+            }
+            HistorySelector::Around(reference) => from(reference).map(|pivot| {
+                let start = pivot.saturating_sub(limit / 2);
+                first(start, entries.len()).collect_vec()
+            }),
+            HistorySelector::Between(start, end) => {
+                match (after(start), from(start), after(end), from(end)) {
+                    (Some(after_start), Some(from_start), Some(after_end), Some(from_end)) => {
+                        if from_start <= from_end {
+                            Some(first(after_start, from_end).collect_vec())
+                        } else {
+                            Some(last(after_end, from_start).collect_vec())
+                        }
+                    }
+                    _ => None,
+                }
+            }
+        };
+        selected
+            .unwrap_or_default()
+            .into_iter()
+            .copied()
+            .collect_vec()
+    }
+
+    /// Latest message accepted by `include` of each channel or conversation
+    /// that was sent between two points in time, oldest first.
+    pub fn targets(
+        &self,
+        include: impl Fn(&HistoryEntry) -> bool,
+        between: (i64, i64),
+        limit: usize,
+        config: &HistoryConfig,
+    ) -> Vec<&HistoryEntry> {
+        let (start, end) = (between.0.min(between.1), between.0.max(between.1));
+        self.entries
+            .keys()
+            .filter_map(|key| {
+                self.retained(key, &include, config)
+                    .into_iter()
+                    .rev()
+                    .find(|entry| entry.time > start && entry.time < end)
+            })
+            .sorted_by_key(|entry| entry.time)
+            .take(limit)
+            .collect_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{MessageKind, MessageTags};
+
+    /// History of `#test` with messages `m1` to `m10`, sent at 1 to 10 seconds.
+    fn history(config: &HistoryConfig) -> MessageHistory {
+        let mut history = MessageHistory::empty(config);
+        for i in 1..=10 {
+            let mut entry = HistoryEntry::new(
+                HistoryEntry::channel_key("#test"),
+                MessageKind::Privmsg,
+                "alice",
+                "#test",
+                &format!("message {}", i),
+                &[],
+                &MessageTags::default(),
+            );
+            entry.msgid = format!("m{}", i);
+            entry.time = i * 1000;
+            history.push(entry, config);
+        }
+        history
+    }
+
+    fn select(selector: HistorySelector, limit: usize) -> Vec<String> {
+        let config = HistoryConfig::default();
+        history(&config)
+            .select("#test", &selector, limit, |_| true, &config)
+            .into_iter()
+            .map(|entry| entry.msgid.clone())
+            .collect_vec()
+    }
+
+    fn msgid(msgid: &str) -> HistoryRef {
+        HistoryRef::MsgId(msgid.to_string())
+    }
+
+    #[test]
+    fn latest() {
+        assert_eq!(
+            select(HistorySelector::Latest(None), 3),
+            ["m8", "m9", "m10"]
+        );
+        assert_eq!(select(HistorySelector::Latest(None), 50).len(), 10);
+        assert_eq!(
+            select(HistorySelector::Latest(Some(msgid("m8"))), 5),
+            ["m9", "m10"]
+        );
+        assert_eq!(
+            select(
+                HistorySelector::Latest(Some(HistoryRef::Timestamp(9000))),
+                5
+            ),
+            ["m10"]
+        );
+    }
+
+    #[test]
+    fn before_and_after() {
+        assert_eq!(
+            select(HistorySelector::Before(msgid("m5")), 2),
+            ["m3", "m4"]
+        );
+        assert_eq!(select(HistorySelector::Before(msgid("m2")), 5), ["m1"]);
+        assert!(select(HistorySelector::Before(msgid("m1")), 5).is_empty());
+        assert_eq!(select(HistorySelector::After(msgid("m5")), 2), ["m6", "m7"]);
+        assert!(select(HistorySelector::After(msgid("m10")), 5).is_empty());
+        assert_eq!(
+            select(HistorySelector::After(HistoryRef::Timestamp(8500)), 5),
+            ["m9", "m10"]
+        );
+    }
+
+    #[test]
+    fn around() {
+        assert_eq!(
+            select(HistorySelector::Around(msgid("m5")), 4),
+            ["m3", "m4", "m5", "m6"]
+        );
+        assert_eq!(
+            select(HistorySelector::Around(msgid("m1")), 2),
+            ["m1", "m2"]
+        );
+    }
+
+    #[test]
+    fn between() {
+        assert_eq!(
+            select(HistorySelector::Between(msgid("m2"), msgid("m6")), 10),
+            ["m3", "m4", "m5"]
+        );
+        assert_eq!(
+            select(HistorySelector::Between(msgid("m2"), msgid("m6")), 2),
+            ["m3", "m4"]
+        );
+        // Reversed bounds select the messages closest to the end
+        assert_eq!(
+            select(HistorySelector::Between(msgid("m6"), msgid("m2")), 2),
+            ["m4", "m5"]
+        );
+    }
+
+    #[test]
+    fn unknown_msgids_select_nothing() {
+        assert!(select(HistorySelector::Before(msgid("unknown")), 5).is_empty());
+        assert!(select(HistorySelector::Latest(Some(msgid("unknown"))), 5).is_empty());
+        assert!(select(HistorySelector::Between(msgid("m1"), msgid("unknown")), 5).is_empty());
+    }
+
+    #[test]
+    fn zero_limit_selects_nothing() {
+        assert!(select(HistorySelector::Latest(None), 0).is_empty());
+        assert!(select(HistorySelector::Around(msgid("m5")), 0).is_empty());
+    }
+
+    #[test]
+    fn max_messages() {
+        let config = HistoryConfig {
+            max_messages: 4,
+            ..HistoryConfig::default()
+        };
+        let selected = history(&config)
+            .select(
+                "#test",
+                &HistorySelector::Latest(None),
+                10,
+                |_| true,
+                &config,
+            )
+            .into_iter()
+            .map(|entry| entry.msgid.clone())
+            .collect_vec();
+        assert_eq!(selected, ["m7", "m8", "m9", "m10"]);
+    }
+
+    #[test]
+    fn invisible_entries_are_skipped() {
+        let config = HistoryConfig::default();
+        let history = history(&config);
+        let selected = history.select(
+            "#test",
+            &HistorySelector::Latest(None),
+            3,
+            |entry| entry.msgid != "m10",
+            &config,
+        );
+        assert_eq!(
+            selected
+                .iter()
+                .map(|entry| entry.msgid.as_str())
+                .collect_vec(),
+            ["m7", "m8", "m9"]
+        );
+    }
+
+    #[test]
+    fn removed_channels_are_forgotten() {
+        let config = HistoryConfig::default();
+        let mut history = history(&config);
+        history.remove(&HistoryEntry::channel_key("#Test")).unwrap();
+        let selected = history.select(
+            "#test",
+            &HistorySelector::Latest(None),
+            10,
+            |_| true,
+            &config,
+        );
+        assert!(selected.is_empty());
+    }
+
+    #[test]
+    fn channel_history_is_not_loaded() {
+        let path = std::env::temp_dir().join(format!("history-{}.jsonl", std::process::id()));
+        let config = HistoryConfig {
+            file: Some(path.to_string_lossy().into_owned()),
+            ..HistoryConfig::default()
+        };
+        let entry = |key: String, target: &str| {
+            HistoryEntry::new(
+                key,
+                MessageKind::Privmsg,
+                "alice",
+                target,
+                "hello",
+                &[],
+                &MessageTags::default(),
+            )
+        };
+        let mut history = MessageHistory::load(&config).unwrap();
+        let private_key = HistoryEntry::conversation_key("alice", "bob");
+        history
+            .add(entry(HistoryEntry::channel_key("#test"), "#test"), &config)
+            .unwrap();
+        history
+            .add(entry(private_key.clone(), "bob"), &config)
+            .unwrap();
+
+        let history = MessageHistory::load(&config).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(history.entries.keys().collect_vec(), [&private_key]);
+        assert_eq!(contents.lines().count(), 1);
+    }
+}
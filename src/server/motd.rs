@@ -0,0 +1,137 @@
+use anyhow::Result;
+use guard::guard;
+use itertools::Itertools;
+use std::fs;
+
+use crate::config::ServerConfig;
+
+/// Maximum width of a single RPL_MOTD line, longer lines are wrapped.
+const MOTD_LINE_WIDTH: usize = 80;
+
+/// Message of the day, either loaded from `motd_file` or taken from `motd`.
+#[derive(Debug)]
+pub struct Motd {
+    lines: Vec<String>,
+}
+
+impl Motd {
+    pub fn load(config: &ServerConfig) -> Result<Self> {
+        let text = match &config.motd_file {
+            Some(path) => fs::read_to_string(path)?,
+            None => config.motd.clone(),
+        };
+        let lines = text
+            .lines()
+            .map(|line| line.trim_end().to_string())
+            .collect_vec();
+        Ok(Self { lines })
+    }
+
+    /// Substitute `{variable}` placeholders and wrap long lines.
+    pub fn render(&self, variables: &[(&str, String)]) -> Vec<String> {
+        self.lines
+            .iter()
+            .map(|line| {
+                variables.iter().fold(line.clone(), |line, (name, value)| {
+                    line.replace(&format!("{{{}}}", name), value)
+                })
+            })
+            .flat_map(|line| wrap(&line, MOTD_LINE_WIDTH))
+            .collect_vec()
+    }
+}
+
+/// Wrap a line at whitespace so that no part exceeds `width` characters.
+/// Words longer than `width` are split.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    if line.chars().count() <= width {
+        return vec![line.to_string()];
+    }
+
+    let mut parts = vec![];
+    let mut current = String::new();
+    for word in line.split(' ') {
+        let mut word = word.to_string();
+        while current.chars().count() + word.chars().count() + 1 > width {
+            if current.is_empty() {
+                // Split words that don't fit on a line of their own
+                let split_at = word.char_indices().nth(width).map(|(i, _)| i);
+                guard!(let Some(split_at) = split_at else { break });
+                parts.push(word[..split_at].to_string());
+                word = word[split_at..].to_string();
+            } else {
+                parts.push(std::mem::take(&mut current));
+            }
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(&word);
+    }
+    parts.push(current);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn motd(text: &str) -> Motd {
+        let config = ServerConfig {
+            motd: text.to_string(),
+            motd_file: None,
+            ..ServerConfig::default()
+        };
+        Motd::load(&config).unwrap()
+    }
+
+    #[test]
+    fn short_lines_are_kept() {
+        assert_eq!(wrap("a b", 80), ["a b"]);
+        assert_eq!(wrap("", 80), [""]);
+    }
+
+    #[test]
+    fn wrap_at_whitespace() {
+        assert_eq!(wrap("aaaa bbbb cccc", 9), ["aaaa bbbb", "cccc"]);
+        let line = "word ".repeat(40);
+        let parts = wrap(line.trim_end(), MOTD_LINE_WIDTH);
+        assert!(parts.len() > 1);
+        assert!(parts
+            .iter()
+            .all(|part| part.chars().count() <= MOTD_LINE_WIDTH));
+        assert_eq!(parts.join(" "), line.trim_end());
+    }
+
+    #[test]
+    fn long_words_are_split() {
+        assert_eq!(
+            wrap(&"x".repeat(20), 8),
+            ["x".repeat(8), "x".repeat(8), "x".repeat(4)]
+        );
+        assert_eq!(wrap("ab cdefghijkl", 4), ["ab", "cdef", "ghij", "kl"]);
+        // Widths count characters, not bytes
+        assert_eq!(wrap(&"é".repeat(10), 4), ["éééé", "éééé", "éé"]);
+    }
+
+    #[test]
+    fn line_breaks_are_kept() {
+        let lines = motd("Welcome\n\nRules:  \n1. Be nice").render(&[]);
+        assert_eq!(lines, ["Welcome", "", "Rules:", "1. Be nice"]);
+    }
+
+    #[test]
+    fn variables() {
+        let lines = motd("{network} has {user_count} users {unknown}\n{network}").render(&[
+            ("network", String::from("Myriad")),
+            ("user_count", String::from("3")),
+        ]);
+        assert_eq!(lines, ["Myriad has 3 users {unknown}", "Myriad"]);
+    }
+
+    #[test]
+    fn substituted_lines_are_wrapped() {
+        let lines = motd("{long}").render(&[("long", "y".repeat(MOTD_LINE_WIDTH + 1))]);
+        assert_eq!(lines, ["y".repeat(MOTD_LINE_WIDTH), String::from("y")]);
+    }
+}
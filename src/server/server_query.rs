@@ -1,8 +1,12 @@
+use chrono::Utc;
 use itertools::Itertools;
 
-use crate::{config::ServerConfig, models::Channel};
+use crate::{
+    config::{Privilege, ServerConfig},
+    models::{format_time, Ban, Channel, HistoryEntry, MessageTags, UserMode},
+};
 
-use super::{Server, User};
+use super::{motd::Motd, Server, User};
 
 pub struct ServerQuery<'a> {
     server: &'a mut Server,
@@ -42,6 +46,36 @@ impl<'a> ServerQuery<'a> {
         &self.server.config
     }
 
+    pub fn server_motd(&self) -> Option<&Motd> {
+        self.server.motd.as_ref()
+    }
+
+    /// Generate the `time`, `msgid` and `account` tags of an event caused by
+    /// the user. They are generated once per event, so all recipients see the same values.
+    pub fn event_tags(&mut self) -> MessageTags {
+        self.server.msgid_counter += 1;
+        let mut tags = MessageTags::default();
+        tags.insert("time", &format_time(Utc::now().timestamp_millis()));
+        tags.insert(
+            "msgid",
+            &format!(
+                "{:x}-{}",
+                self.server.startup_time.timestamp_millis(),
+                self.server.msgid_counter
+            ),
+        );
+        if let Some(account) = &self.user().account {
+            tags.insert("account", account);
+        }
+        tags
+    }
+
+    /// Generate a unique reference tag for a BATCH.
+    pub fn batch_reference(&mut self) -> String {
+        self.server.batch_counter += 1;
+        format!("{:x}", self.server.batch_counter)
+    }
+
     //
     // User
     //
@@ -61,6 +95,13 @@ impl<'a> ServerQuery<'a> {
             .find(|user| user.client_id == client_id)
     }
 
+    pub fn user_find_by_client_id_mut(&mut self, client_id: u64) -> Option<&mut User> {
+        self.server
+            .users
+            .iter_mut()
+            .find(|user| user.client_id == client_id)
+    }
+
     pub fn user_find_by_nickname(&self, nickname: &str) -> Option<&User> {
         self.server.users.iter().find(|user| {
             if let Some(user_nickname) = &user.nickname {
@@ -71,12 +112,97 @@ impl<'a> ServerQuery<'a> {
         })
     }
 
-    pub fn user_count(&self) -> usize {
-        self.server.users.len()
+    /// Identity private message history is kept for: the account of a user,
+    /// or otherwise their connection, so later users of a nickname can't read it.
+    pub fn history_identity(&self, client_id: u64) -> Option<String> {
+        let user = self.user_find_by_client_id(client_id)?;
+        Some(match &user.account {
+            Some(account) => format!("account:{}", account.to_ascii_lowercase()),
+            None => format!(
+                "client:{}:{}",
+                self.server.startup_time.timestamp_millis(),
+                client_id
+            ),
+        })
+    }
+
+    pub fn registered_user_count(&self) -> usize {
+        self.server
+            .users
+            .iter()
+            .filter(|user| user.registered)
+            .count()
+    }
+
+    pub fn invisible_user_count(&self) -> usize {
+        self.server
+            .users
+            .iter()
+            .filter(|user| user.registered && user.has_mode(UserMode::Invisible))
+            .count()
+    }
+
+    pub fn operator_count(&self) -> usize {
+        self.server
+            .users
+            .iter()
+            .filter(|user| user.has_mode(UserMode::Operator))
+            .count()
+    }
+
+    /// Connections that have not completed registration yet.
+    pub fn unknown_connection_count(&self) -> usize {
+        self.server
+            .users
+            .iter()
+            .filter(|user| !user.registered)
+            .count()
+    }
+
+    pub fn max_user_count(&self) -> usize {
+        self.server.max_user_count
+    }
+
+    /// Record the current number of registered users as the peak, if it is one.
+    pub fn update_max_user_count(&mut self) {
+        self.server.max_user_count = self.server.max_user_count.max(self.registered_user_count());
+    }
+
+    pub fn user_has_privilege(&self, privilege: Privilege) -> bool {
+        self.client_has_privilege(self.client_id, privilege)
+    }
+
+    pub fn client_has_privilege(&self, client_id: u64, privilege: Privilege) -> bool {
+        self.user_find_by_client_id(client_id)
+            .filter(|user| user.has_mode(UserMode::Operator))
+            .and_then(|user| user.oper_name.as_ref())
+            .is_some_and(|oper_name| {
+                self.server
+                    .config
+                    .oper_privileges(oper_name)
+                    .contains(&privilege)
+            })
+    }
+
+    /// Clients whose connection matches the ban.
+    pub fn banned_clients(&self, ban: &Ban) -> Vec<u64> {
+        self.server
+            .users
+            .iter()
+            .filter(|user| ban.matches(user.username.as_deref(), &user.hosts(), user.ip))
+            .map(|user| user.client_id)
+            .collect_vec()
     }
 
-    pub fn user_nick_unsafe(&self) -> String {
-        self.user().nickname.clone().unwrap()
+    /// Clients receiving server notices.
+    pub fn server_notice_recipients(&self) -> Vec<u64> {
+        self.server
+            .users
+            .iter()
+            .filter(|user| user.has_mode(UserMode::ServerNotices))
+            .filter(|user| self.client_has_privilege(user.client_id, Privilege::ServerNotices))
+            .map(|user| user.client_id)
+            .collect_vec()
     }
 
     pub fn user_mut(&mut self) -> &mut User {
@@ -96,24 +222,26 @@ impl<'a> ServerQuery<'a> {
     // Channel
     //
 
-    pub fn channel_create(&mut self, name: String) {
-        let channel = Channel::new(name);
-        self.server.channels.push(channel);
+    pub fn channel_count(&self) -> usize {
+        self.server.channels.len()
     }
 
-    pub fn channel_find(&self, name: &str) -> Option<&Channel> {
+    pub fn channels(&self) -> &[Channel] {
+        &self.server.channels[..]
+    }
+
+    pub fn channel_find_mut(&mut self, name: &str) -> Option<&mut Channel> {
         self.server
             .channels
-            .iter()
+            .iter_mut()
             .find(|channel| channel.name() == name)
     }
 
-    pub fn channel_exists(&self, name: &str) -> bool {
+    pub fn channel_find(&self, name: &str) -> Option<&Channel> {
         self.server
             .channels
             .iter()
             .find(|channel| channel.name() == name)
-            .is_some()
     }
 
     pub fn channel_get_or_create(&mut self, name: &str) -> &mut Channel {
@@ -135,6 +263,22 @@ impl<'a> ServerQuery<'a> {
         }
     }
 
+    /// Channels without users cease to exist, along with their history.
+    pub fn channel_remove_empty(&mut self) {
+        let server_mut = self.server_mut();
+        let (empty, channels): (Vec<Channel>, Vec<Channel>) = server_mut
+            .channels
+            .drain(..)
+            .partition(|channel| channel.clients().is_empty());
+        server_mut.channels = channels;
+        for channel in empty {
+            let key = HistoryEntry::channel_key(channel.name());
+            if let Err(err) = server_mut.history.remove(&key) {
+                println!("[Server] Unable to write message history: {}", err);
+            }
+        }
+    }
+
     pub fn channel_users(&self, name: &str) -> Option<Vec<&User>> {
         self.channel_find(name).map(|channel| {
             channel
@@ -144,4 +288,22 @@ impl<'a> ServerQuery<'a> {
                 .collect_vec()
         })
     }
+
+    pub fn channels_of_client(&self, client_id: u64) -> Vec<&Channel> {
+        self.server
+            .channels
+            .iter()
+            .filter(|channel| channel.clients().contains(&client_id))
+            .collect_vec()
+    }
+
+    /// Clients sharing at least one channel with the given client (excluding itself).
+    pub fn channel_peers(&self, client_id: u64) -> Vec<u64> {
+        self.channels_of_client(client_id)
+            .iter()
+            .flat_map(|channel| channel.clients().iter().copied())
+            .filter(|peer_id| *peer_id != client_id)
+            .unique()
+            .collect_vec()
+    }
 }